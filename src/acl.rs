@@ -0,0 +1,253 @@
+use formulatrix_uploader::{OwnershipPolicy, Visit};
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+const ACL_EA_VERSION: u32 = 2;
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+
+const ACCESS_XATTR: &str = "system.posix_acl_access";
+const DEFAULT_XATTR: &str = "system.posix_acl_default";
+
+/// Read, write and execute
+pub const RWX: u16 = 0o7;
+
+/// One entry of the `posix_acl_xattr` layout used by the kernel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct AclEntry {
+    tag: u16,
+    id: u32,
+    perm: u16,
+}
+
+/// Resolve a user name to its uid through the system password database
+pub fn lookup_uid(user: &str) -> io::Result<u32> {
+    let name = CString::new(user).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut buffer = vec![0 as libc::c_char; 16384];
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+
+    let status = unsafe { libc::getpwnam_r(name.as_ptr(), &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+    if status != 0 {
+        return Err(io::Error::from_raw_os_error(status));
+    }
+    if result.is_null() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("No such user: {}", user)));
+    }
+    Ok(passwd.pw_uid)
+}
+
+/// Grant `perm` to the user with `uid`, merging into any existing ACL the way `setfacl -m` does.
+/// Directories also get a default ACL so files created inside inherit the grant.
+pub fn grant_user(path: &Path, uid: u32, perm: u16) -> io::Result<()> {
+    let mode = (path.metadata()?.permissions().mode() & 0o777) as u16;
+
+    let access = read_acl(path, ACCESS_XATTR)?.unwrap_or_else(|| from_mode(mode));
+    write_acl(path, ACCESS_XATTR, &with_user(access, uid, perm))?;
+
+    if path.is_dir() {
+        let default = read_acl(path, DEFAULT_XATTR)?.unwrap_or_else(|| from_mode(mode));
+        write_acl(path, DEFAULT_XATTR, &with_user(default, uid, perm))?;
+    }
+
+    Ok(())
+}
+
+/// The minimal ACL equivalent to the permission bits
+fn from_mode(mode: u16) -> Vec<AclEntry> {
+    vec![
+        AclEntry { tag: ACL_USER_OBJ, id: ACL_UNDEFINED_ID, perm: (mode >> 6) & 0o7 },
+        AclEntry { tag: ACL_GROUP_OBJ, id: ACL_UNDEFINED_ID, perm: (mode >> 3) & 0o7 },
+        AclEntry { tag: ACL_OTHER, id: ACL_UNDEFINED_ID, perm: mode & 0o7 },
+    ]
+}
+
+/// Replace the named user entry and recalculate the mask as the union of the group class
+fn with_user(entries: Vec<AclEntry>, uid: u32, perm: u16) -> Vec<AclEntry> {
+    let mut entries: Vec<AclEntry> = entries
+        .into_iter()
+        .filter(|entry| entry.tag != ACL_MASK && !(entry.tag == ACL_USER && entry.id == uid))
+        .collect();
+    entries.push(AclEntry { tag: ACL_USER, id: uid, perm });
+
+    let mask = entries
+        .iter()
+        .filter(|entry| matches!(entry.tag, ACL_USER | ACL_GROUP_OBJ | ACL_GROUP))
+        .fold(0, |mask, entry| mask | entry.perm);
+    entries.push(AclEntry { tag: ACL_MASK, id: ACL_UNDEFINED_ID, perm: mask });
+
+    entries.sort_by_key(|entry| (entry.tag, entry.id));
+    entries
+}
+
+fn read_acl(path: &Path, name: &str) -> io::Result<Option<Vec<AclEntry>>> {
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let name = CString::new(name).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+    let size = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+    if size < 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::ENODATA) => Ok(None),
+            _ => Err(err),
+        };
+    }
+
+    let mut value = vec![0u8; size as usize];
+    let size = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), value.as_mut_ptr() as *mut libc::c_void, value.len()) };
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    value.truncate(size as usize);
+    decode(&value).map(Some)
+}
+
+fn write_acl(path: &Path, name: &str, entries: &[AclEntry]) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let name = CString::new(name).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let value = encode(entries);
+
+    let status = unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), value.as_ptr() as *const libc::c_void, value.len(), 0) };
+    if status != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn decode(value: &[u8]) -> io::Result<Vec<AclEntry>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Malformed POSIX ACL");
+    let version = value.get(..4).map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])).ok_or_else(invalid)?;
+    if version != ACL_EA_VERSION || !(value.len() - 4).is_multiple_of(8) {
+        return Err(invalid());
+    }
+
+    Ok(value[4..]
+        .chunks_exact(8)
+        .map(|entry| AclEntry {
+            tag: u16::from_le_bytes([entry[0], entry[1]]),
+            perm: u16::from_le_bytes([entry[2], entry[3]]),
+            id: u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]),
+        })
+        .collect())
+}
+
+fn encode(entries: &[AclEntry]) -> Vec<u8> {
+    let mut value: Vec<u8> = ACL_EA_VERSION.to_le_bytes().to_vec();
+    for entry in entries {
+        value.extend_from_slice(&entry.tag.to_le_bytes());
+        value.extend_from_slice(&entry.perm.to_le_bytes());
+        value.extend_from_slice(&entry.id.to_le_bytes());
+    }
+    value
+}
+
+/// Owner, group and permission bits resolved for one visit, `None` leaves the value as created
+#[derive(Debug, Clone, Default)]
+pub struct Ownership {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub dir_mode: Option<u32>,
+    pub file_mode: Option<u32>,
+}
+
+/// Look up the configured owner and group, substituting `{proposal}` and `{visit}` in the group name
+pub fn resolve_ownership(policy: &OwnershipPolicy, visit: &Visit) -> io::Result<Ownership> {
+    let parse_mode = |mode: &String| u32::from_str_radix(mode, 8).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid mode {}: {}", mode, err)));
+
+    Ok(Ownership {
+        uid: policy.owner.as_deref().map(lookup_uid).transpose()?,
+        gid: policy.group
+            .as_ref()
+            .map(|group| lookup_gid(&group.replace("{proposal}", &visit.proposal()).replace("{visit}", &visit.to_string())))
+            .transpose()?,
+        dir_mode: policy.dir_mode.as_ref().map(parse_mode).transpose()?,
+        file_mode: policy.file_mode.as_ref().map(parse_mode).transpose()?,
+    })
+}
+
+/// Resolve a group name to its gid through the system group database
+pub fn lookup_gid(group: &str) -> io::Result<u32> {
+    let name = CString::new(group).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut buffer = vec![0 as libc::c_char; 65536];
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::group = std::ptr::null_mut();
+
+    let status = unsafe { libc::getgrnam_r(name.as_ptr(), &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+    if status != 0 {
+        return Err(io::Error::from_raw_os_error(status));
+    }
+    if result.is_null() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("No such group: {}", group)));
+    }
+    Ok(entry.gr_gid)
+}
+
+/// Change owner and group, then the directory or file mode. Ownership is changed first
+/// because a chown can clear the setgid bit that a mode like 2770 relies on.
+pub fn apply_ownership(path: &Path, ownership: &Ownership) -> io::Result<()> {
+    if ownership.uid.is_some() || ownership.gid.is_some() {
+        let c_path = CString::new(path.as_os_str().as_bytes()).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let status = unsafe { libc::chown(c_path.as_ptr(), ownership.uid.unwrap_or(u32::MAX), ownership.gid.unwrap_or(u32::MAX)) };
+        if status != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    let mode = if path.is_dir() { ownership.dir_mode } else { ownership.file_mode };
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_and_decode_round_trip() {
+        let entries = from_mode(0o750);
+        let value = encode(&entries);
+        assert_eq!(value.len(), 4 + 8 * entries.len());
+        assert_eq!(&value[..4], &[2, 0, 0, 0]);
+        assert_eq!(decode(&value).unwrap(), entries);
+    }
+
+    #[test]
+    fn decode_rejects_malformed_values() {
+        assert!(decode(&[]).is_err());
+        assert!(decode(&[1, 0, 0, 0]).is_err());
+        assert!(decode(&[2, 0, 0, 0, 1, 0, 7]).is_err());
+    }
+
+    #[test]
+    fn with_user_adds_entry_and_mask() {
+        let entries = with_user(from_mode(0o750), 1000, RWX);
+        assert_eq!(entries, vec![
+            AclEntry { tag: ACL_USER_OBJ, id: ACL_UNDEFINED_ID, perm: 0o7 },
+            AclEntry { tag: ACL_USER, id: 1000, perm: RWX },
+            AclEntry { tag: ACL_GROUP_OBJ, id: ACL_UNDEFINED_ID, perm: 0o5 },
+            AclEntry { tag: ACL_MASK, id: ACL_UNDEFINED_ID, perm: 0o7 },
+            AclEntry { tag: ACL_OTHER, id: ACL_UNDEFINED_ID, perm: 0o0 },
+        ]);
+    }
+
+    #[test]
+    fn with_user_replaces_existing_grant() {
+        let entries = with_user(with_user(from_mode(0o700), 1000, RWX), 1000, 0o4);
+        let users: Vec<&AclEntry> = entries.iter().filter(|entry| entry.tag == ACL_USER).collect();
+        assert_eq!(users, vec![&AclEntry { tag: ACL_USER, id: 1000, perm: 0o4 }]);
+        let mask = entries.iter().find(|entry| entry.tag == ACL_MASK).unwrap();
+        assert_eq!(mask.perm, 0o4);
+    }
+}
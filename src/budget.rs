@@ -0,0 +1,104 @@
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Caps the memory held by decoded images across all worker threads.
+/// Threads block in `acquire` until enough of the budget has been released.
+pub struct MemoryBudget {
+    limit: u64,
+    in_use: Mutex<u64>,
+    released: Condvar,
+}
+
+/// Budget reserved for one decode, returned when dropped
+pub struct MemoryPermit<'a> {
+    budget: &'a MemoryBudget,
+    bytes: u64,
+}
+
+impl MemoryBudget {
+    /// A `limit` of zero disables the budget
+    pub fn new(limit: u64) -> Self {
+        Self { limit, in_use: Mutex::new(0), released: Condvar::new() }
+    }
+
+    /// Reserve `bytes` of the budget, waiting for other decodes to finish if needed.
+    /// Requests larger than the whole budget are clamped so they run alone rather than never.
+    pub fn acquire(&self, bytes: u64) -> MemoryPermit<'_> {
+        if self.limit == 0 {
+            return MemoryPermit { budget: self, bytes: 0 };
+        }
+
+        let bytes = bytes.min(self.limit);
+        let mut in_use = self.in_use.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        while *in_use + bytes > self.limit {
+            in_use = self.released.wait(in_use).unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        *in_use += bytes;
+        MemoryPermit { budget: self, bytes }
+    }
+}
+
+impl Drop for MemoryPermit<'_> {
+    fn drop(&mut self) {
+        if self.bytes == 0 {
+            return;
+        }
+        let mut in_use = self.budget.in_use.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        *in_use -= self.bytes;
+        self.budget.released.notify_all();
+    }
+}
+
+/// Token bucket limiting the bytes per second read from the holding directory, 0 for no limit
+pub struct IoThrottle {
+    bytes_per_sec: u64,
+    state: Mutex<(Instant, f64)>,
+}
+
+impl IoThrottle {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self { bytes_per_sec, state: Mutex::new((Instant::now(), bytes_per_sec as f64)) }
+    }
+
+    /// Take `bytes` from the bucket, sleeping until enough has refilled.
+    /// The bucket holds at most one second of transfer so idle time does not build up a burst.
+    pub fn consume(&self, bytes: u64) {
+        if self.bytes_per_sec == 0 {
+            return;
+        }
+
+        let wait = {
+            let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let (last, available) = *state;
+            let now = Instant::now();
+            let rate = self.bytes_per_sec as f64;
+            let available = (available + now.duration_since(last).as_secs_f64() * rate).min(rate) - bytes as f64;
+            *state = (now, available);
+            if available < 0.0 {
+                Duration::from_secs_f64(-available / rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// The memory and I/O limits shared by every thread of a worker
+pub struct ResourceLimits {
+    pub memory: MemoryBudget,
+    pub io: IoThrottle,
+}
+
+impl ResourceLimits {
+    pub fn new(memory_mb: u64, io_mb_per_sec: u64) -> Self {
+        Self {
+            memory: MemoryBudget::new(memory_mb * 1024 * 1024),
+            io: IoThrottle::new(io_mb_per_sec * 1024 * 1024),
+        }
+    }
+}
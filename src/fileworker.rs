@@ -1,113 +1,309 @@
+use crate::focus::{extended_focus, group_stacks};
+use crate::preview::{preview_paths, write_previews};
+use crate::acl::{apply_ownership, grant_user, lookup_uid, resolve_ownership, Ownership, RWX};
+use crate::budget::ResourceLimits;
+use crate::signals::{self, shutdown_requested};
+use crate::stability::stable_files;
+use crate::tiffio::{
+    can_stream, decoded_size, has_tiff_extension, is_tiff, read_pages, stream_flip_vertical, streaming_size, write_pages, TiffPage
+};
 use crate::ispyb::{
-    populate_test_data, fetch_inspection_info, fetch_visit_info, populate_test_data_for_inspection
+    populate_test_data, fetch_inspection_info, fetch_visit_info, populate_test_data_for_inspection,
+    fetch_sample_id, insert_sample_image, update_sample_image_path, fetch_imager_id,
+    fetch_inspection_progress, complete_inspection, update_container_imager, fetch_container_info,
+    fetch_nearest_inspection_id, fetch_sample_image_id_by_path, update_sample_image_comments,
+    fetch_session_start_offset, fetch_inspection_image_id, fetch_imaged_locations
 };
 
 use std::{collections::HashSet, path::PathBuf};
-use formulatrix_uploader::{Config, VisitInfo, XmlDatum};
+use formulatrix_uploader::{
+    parse_imaged_at, AclPolicy, Config, InspectionGating, InspectionInfo, PlateTypes, Transform, Visit, VisitInfo, XmlDatum, ZSlice
+};
 use std::collections::HashMap;
 use glob::glob;
 use anyhow::{Context, Error, Ok, Result};
 use mysql::*;
+use mysql::prelude::Queryable;
 use anyhow::anyhow;
 use std::path::Path;
 use std::result::Result::Ok as OtherOk;
 use std::fs;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::io::prelude::*;
-use std::process::Command;
-use image::{open, DynamicImage, imageops};
+use image::{open, DynamicImage, ImageFormat};
+use image::codecs::jpeg::JpegEncoder;
 use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
 use elementtree::{self, Element};
 
+/// The outcome of placing each file of a job
+type FileResults = Vec<Result<PathBuf, Error>>;
+
 pub trait WorkerShared {
     fn process_job(&self, pool: &Pool) -> Result<(),Error>;
 
-    fn get_visit_dir(&self, query_result: VisitInfo, upload_dir: String) -> Result<PathBuf,Error>{    
-        let visit = query_result.visit.unwrap();
-        let proposal = if let Some(index) = visit.find('-') {
-            visit[..index].to_string()
-        } else {
-            visit.clone()
-        };
-    
-        let new_root: PathBuf = Path::new(&upload_dir)
-            .join(Path::new(&proposal))
-            .join(Path::new(&visit));
-                        
-        let old_root: PathBuf = if let Some(year) = query_result.year {
-            Path::new(&upload_dir)
-            .join(Path::new(&year))
-            .join(Path::new(&visit))
-        } else{
-            PathBuf::new()
-        };
-        
-        match fs::canonicalize(old_root.clone()) {
-            OtherOk(path) => Ok(path),
-            Err(_) => {
-                match fs::canonicalize(new_root.clone()) {
-                    OtherOk(path) => Ok(path),
-                    Err(_) => Err(anyhow!(format!(
-                        "Visit directory path does not exist. Tried old root: {} and new root: {}",
-                        old_root.to_string_lossy().into_owned(),
-                        new_root.to_string_lossy().into_owned()
-                    ))),
+    /// Expand each visit directory layout template. Templates use the `{upload}`, `{year}`, `{visit}`,
+    /// `{proposal}` and `{beamline}` placeholders and expand to `None` when a placeholder has no value.
+    fn expand_visit_templates<'a>(&self, visit: &Visit, query_result: &VisitInfo, upload_dir: &str, templates: &'a [String]) -> Vec<(&'a String, Option<String>)>{
+        let placeholders: [(&str, Option<String>); 5] = [
+            ("{upload}", Some(upload_dir.to_string())),
+            ("{year}", query_result.year.clone()),
+            ("{visit}", Some(visit.to_string())),
+            ("{proposal}", Some(visit.proposal())),
+            ("{beamline}", query_result.beamline.clone()),
+        ];
+
+        templates
+            .iter()
+            .map(|template| {
+                let candidate: Option<String> = placeholders
+                    .iter()
+                    .try_fold(template.clone(), |path, (placeholder, value)| match value {
+                        Some(value) => Some(path.replace(placeholder, value)),
+                        None if path.contains(placeholder) => None,
+                        None => Some(path),
+                    })
+                    .filter(|path| !path.contains('{'));
+                (template, candidate)
+            })
+            .collect()
+    }
+
+    /// Find the visit directory by trying each layout template in order
+    fn get_visit_dir(&self, visit: &Visit, query_result: &VisitInfo, upload_dir: &str, templates: &[String]) -> Result<PathBuf,Error>{
+        let mut tried: Vec<String> = Vec::new();
+        for (template, candidate) in self.expand_visit_templates(visit, query_result, upload_dir, templates) {
+            let Some(candidate) = candidate else {
+                tried.push(format!("{} (no value for a placeholder)", template));
+                continue
+            };
+
+            match fs::canonicalize(&candidate) {
+                OtherOk(path) => {
+                    println!("Visit directory {} matched template {}", path.to_string_lossy(), template);
+                    return Ok(path)
                 }
+                Err(_) => tried.push(candidate),
             }
         }
+
+        Err(anyhow!(format!("Visit directory path does not exist. Tried: {}", tried.join(", "))))
+    }
+
+    /// Find the visit directory, or create it from the first template that resolves when
+    /// `create_visit_dir` is configured and the session starts within its window of today
+    fn find_or_create_visit_dir(&self, visit: &Visit, query_result: &VisitInfo, config: &Config, pool: &Pool, ownership: &Ownership) -> Result<PathBuf,Error>{
+        let missing: Error = match self.get_visit_dir(visit, query_result, &config.upload_dir, &config.visit_dir_templates) {
+            OtherOk(path) => return Ok(path),
+            Err(err) => err,
+        };
+        let Some(policy) = &config.create_visit_dir else {
+            return Err(missing)
+        };
+
+        let days: Option<i64> = fetch_session_start_offset(visit, &mut pool.get_conn()?)
+            .context(format!("Failed to retrieve start date of session {}", visit))?;
+        match days {
+            Some(days) if days.unsigned_abs() <= policy.window_days as u64 => {}
+            Some(days) => return Err(missing.context(format!(
+                "Session {} start date is {} days from today, outside the {} day window for creating its directory",
+                visit, days.unsigned_abs(), policy.window_days))),
+            None => return Err(missing.context(format!("Session {} was not found in ISPyB or has no start date", visit))),
+        }
+
+        let path: PathBuf = self.expand_visit_templates(visit, query_result, &config.upload_dir, &config.visit_dir_templates)
+            .into_iter()
+            .find_map(|(_, candidate)| candidate)
+            .map(PathBuf::from)
+            .context(format!("No visit directory template resolves for session {}", visit))?;
+
+        println!("Creating visit directory {:?} for session {}", path, visit);
+        // Directories above the visit, such as the year, are shared between proposals and keep the default owner and mode
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).context(format!("Failed to create directory {:?}", parent))?;
+        }
+        self.make_dirs(&path, config.web_user.clone(), config.acl_policy, ownership)
+            .context(format!("Failed to create visit directory {:?}", path))?;
+        Ok(path)
     }
 
-    fn make_dirs(&self, path: &Path, web_user: String) -> Result<(), Error>{
+    /// Create `path` and give the web user rwx, with a default ACL so new files inherit it,
+    /// on every directory that had to be created. The ownership policy is per visit, so `path`
+    /// must be the visit directory or lie inside one that exists.
+    fn make_dirs(&self, path: &Path, web_user: String, acl_policy: AclPolicy, ownership: &Ownership) -> Result<(), Error>{
         if path.exists() {
-            Ok(())
-        } else {
-            fs::create_dir_all(path).map_err(anyhow::Error::from)?;
-        
-            match Command::new("/usr/bin/setfacl")
-            .args(["-R", "-m", &format!("u:{}:rwx", web_user), path.to_string_lossy().as_ref()])
-            .status() {
-                OtherOk(setfacl_status) => {
-                    if setfacl_status.success() {
-                        Ok(())
-                    } else {
-                        println!("setfacl failed with exit code: {}", setfacl_status.code().unwrap_or(-1));
-                        Ok(())
-                    }
-                }
-                Err(e) => {
-                    println!("setfacl process failed with: {}", e);
-                    Ok(())
-                }
+            return Ok(())
+        }
+
+        let created: Vec<PathBuf> = path
+            .ancestors()
+            .take_while(|ancestor| !ancestor.as_os_str().is_empty() && !ancestor.exists())
+            .map(Path::to_path_buf)
+            .collect();
+        fs::create_dir_all(path).map_err(anyhow::Error::from)?;
+        self.set_ownership(&created, ownership)?;
+
+        let outcome = lookup_uid(&web_user)
+            .and_then(|uid| created.iter().rev().try_for_each(|dir| grant_user(dir, uid, RWX)));
+
+        match (outcome, acl_policy) {
+            (OtherOk(_), _) | (Err(_), AclPolicy::Ignore) => Ok(()),
+            (Err(err), AclPolicy::Warn) => {
+                println!("Failed to set ACL for {} on {:?}: {}", web_user, path, err);
+                Ok(())
             }
+            (Err(err), AclPolicy::Error) => Err(anyhow!(format!("Failed to set ACL for {} on {:?}: {}", web_user, path, err))),
         }
     }
 
-    fn move_dir(&self, src: &Path, target: &Path) -> Result<(),Error>{
-        let new_filename = target.join(src.file_name().unwrap());
+    /// Apply the configured owner, group and mode to created or placed paths
+    fn set_ownership(&self, paths: &[PathBuf], ownership: &Ownership) -> Result<(), Error>{
+        for path in paths.iter().rev() {
+            apply_ownership(path, ownership).context(format!("Failed to set ownership of {:?}", path))?;
+        }
+        Ok(())
+    }
 
-        let has_tiff_extension = matches!(src.extension(), Some(ext) if ext == "tiff");
+    /// Convert a drop location like `G01.1` into the ISPyB sample location for the plate type
+    fn get_position(&self, drop: &str, container_type: &str, types: &PlateTypes) -> Result<u32, Error>{
+        let layout = types.get(container_type)
+            .filter(|layout| layout.well_per_row > 0 && layout.drops_per_well > 0)
+            .context(format!("No plate layout configured for plate type: {}", container_type))?;
+        let (well, drop_number) = drop.split_once('.').context(format!("Failed to parse drop: {}", drop))?;
+        let row = well.chars().next().filter(|c| c.is_ascii_uppercase()).context(format!("Failed to parse well row: {}", well))? as u32 - 'A' as u32;
+        let column: u32 = well[1..].parse::<u32>().ok()
+            .and_then(|column| column.checked_sub(1))
+            .context(format!("Failed to parse well column: {}", well))?;
+        let drop_number: u32 = drop_number.parse().context(format!("Failed to parse drop number: {}", drop))?;
 
-        if has_tiff_extension {
-            let img: DynamicImage = open(src)?;
-            let flipped_image = imageops::flip_vertical(&img);
-            flipped_image.save(&new_filename)?;
-            //fs::remove_file(src).context("Failed to delete file from source")?;
-        } else {
+        let drops_per_well = layout.drops_per_well as u32;
+        let well_per_row = layout.well_per_row as u32;
+        Ok(row * well_per_row * drops_per_well + column * drops_per_well + drop_number)
+    }
+
+    /// Place a file in the target directory, applying the configured transforms to images.
+    /// Without a configured pipeline TIFFs are flipped vertically and everything else is copied untouched.
+    fn move_dir(&self, src: &Path, target: &Path, transforms: &Option<Vec<Transform>>, limits: &ResourceLimits) -> Result<PathBuf,Error>{
+        let new_filename = target.join(src.file_name().context("Source has no file name")?);
+
+        let src_is_tiff = is_tiff(src);
+
+        let transforms: Vec<Transform> = match transforms {
+            Some(transforms) => transforms.clone(),
+            None if src_is_tiff => vec![Transform::FlipVertical],
+            None => Vec::new(),
+        };
+
+        limits.io.consume(fs::metadata(src)?.len());
+
+        if transforms.is_empty() || (!src_is_tiff && ImageFormat::from_path(src).is_err()) {
             fs::copy(src, &new_filename).context("Failed to copy file")?;
             //fs::remove_file(src).context("Failed to delete file from source")?;
+            return Ok(new_filename)
         }
 
-        Ok(())
+        let placed = self.transform_image(src, &new_filename, &transforms, limits)?;
+        //fs::remove_file(src).context("Failed to delete file from source")?;
+        Ok(placed)
+    }
+
+    /// Decode an image, apply each transform in order and encode it to `target`,
+    /// returning the written path which changes if the pipeline converts the format.
+    /// TIFF to TIFF keeps every page, the sample bit depth and, unless the pipeline strips metadata,
+    /// the resolution tags. A plain vertical flip is streamed a strip at a time instead of decoding whole pages.
+    fn transform_image(&self, src: &Path, target: &Path, transforms: &[Transform], limits: &ResourceLimits) -> Result<PathBuf,Error>{
+        let mut target_path: PathBuf = target.to_path_buf();
+        let mut quality: Option<u8> = None;
+        let mut converts = false;
+
+        for transform in transforms {
+            match transform {
+                Transform::Convert { format } => {
+                    target_path.set_extension(format);
+                    converts = true;
+                },
+                Transform::Quality { quality: jpeg_quality } => quality = Some(*jpeg_quality),
+                _ => {},
+            }
+        }
+
+        // Without a convert step the output keeps the source format, whatever the file is called
+        let src_is_tiff = is_tiff(src);
+        if src_is_tiff && (!converts || has_tiff_extension(&target_path)) {
+            if transforms == [Transform::FlipVertical] && can_stream(src).unwrap_or(false) {
+                let _permit = limits.memory.acquire(streaming_size(src)?);
+                stream_flip_vertical(src, &target_path)?;
+                return Ok(target_path)
+            }
+
+            let _permit = limits.memory.acquire(2 * decoded_size(src)?);
+            let swaps_axes = transforms
+                .iter()
+                .filter(|transform| matches!(transform, Transform::Rotate { degrees: 90 } | Transform::Rotate { degrees: 270 }))
+                .count() % 2 == 1;
+            let strip_metadata = transforms.contains(&Transform::StripMetadata);
+
+            let mut pages: Vec<TiffPage> = read_pages(src)?;
+            for page in pages.iter_mut() {
+                page.img = self.apply_transforms(&page.img, transforms)?;
+                if swaps_axes {
+                    std::mem::swap(&mut page.x_resolution, &mut page.y_resolution);
+                }
+                if strip_metadata {
+                    page.x_resolution = None;
+                    page.y_resolution = None;
+                    page.resolution_unit = None;
+                }
+            }
+            write_pages(&target_path, &pages)?;
+            return Ok(target_path)
+        }
+
+        let (width, height) = image::io::Reader::open(src)?.with_guessed_format()?.into_dimensions()?;
+        let _permit = limits.memory.acquire(2 * 8 * width as u64 * height as u64);
+        let img: DynamicImage = image::io::Reader::open(src)?.with_guessed_format()?.decode()?;
+        let img: DynamicImage = self.apply_transforms(&img, transforms)?;
+
+        match ImageFormat::from_path(&target_path)? {
+            ImageFormat::Jpeg => {
+                let file = fs::File::create(&target_path)?;
+                JpegEncoder::new_with_quality(file, quality.unwrap_or(75)).encode_image(&img.to_rgb8())?;
+            }
+            _ => img.save(&target_path)?,
+        }
+
+        Ok(target_path)
+    }
+
+    /// Apply the geometric steps of the pipeline, keeping the pixel type of the image
+    fn apply_transforms(&self, img: &DynamicImage, transforms: &[Transform]) -> Result<DynamicImage,Error>{
+        let mut img: DynamicImage = img.clone();
+        for transform in transforms {
+            match transform {
+                Transform::FlipHorizontal => img = img.fliph(),
+                Transform::FlipVertical => img = img.flipv(),
+                Transform::Rotate { degrees: 90 } => img = img.rotate90(),
+                Transform::Rotate { degrees: 180 } => img = img.rotate180(),
+                Transform::Rotate { degrees: 270 } => img = img.rotate270(),
+                Transform::Rotate { degrees } => return Err(anyhow!(format!("Unsupported rotation: {} degrees", degrees))),
+                Transform::Crop { x, y, width, height } => img = img.crop_imm(*x, *y, *width, *height),
+                Transform::Convert { .. } | Transform::Quality { .. } | Transform::StripMetadata => {},
+            }
+        }
+        Ok(img)
     }
 }
 
 pub struct ZWorker {
     pub config: Config,
     pub date_dirs: Vec<PathBuf>,
+    pub limits: ResourceLimits,
 }
 
 impl ZWorker {
     pub fn new(config: Config, date_dirs:Vec<PathBuf>) -> Self {
-        Self { config, date_dirs }
+        let limits = ResourceLimits::new(config.decode_memory_mb, config.io_throttle_mb_s);
+        Self { config, date_dirs, limits }
     }
 
     pub fn get_container_dict(&self, date_dirs:Vec<PathBuf>) -> Result<HashMap<String, String>, Error>{
@@ -143,50 +339,364 @@ impl ZWorker {
         Ok(containers)
     }
 
-    pub fn get_target_and_move(&self, barcode: &String, date_dir: &String, pool: &Pool, holding_dir: String)  -> Result<Vec<Result<PathBuf, Error>>, Error> {
+    /// Where a slice is kept once placed, from the `z_layout` template relative to the visit directory
+    pub fn final_path(&self, visit_dir: &Path, slice: &ZSlice, ext: &str) -> PathBuf {
+        let timestamp: String = slice.imaged_at.chars().filter(|c| c.is_ascii_digit()).collect();
+        let relative: String = self.config.z_layout
+            .replace("{barcode}", &slice.barcode)
+            .replace("{date}", &timestamp[..8])
+            .replace("{time}", &timestamp[8..])
+            .replace("{well}", &slice.well)
+            .replace("{drop}", &slice.drop.to_string())
+            .replace("{height}", &slice.z_height.to_string())
+            .replace("{ext}", ext);
+        visit_dir.join(relative)
+    }
+
+    /// The extension a file will have once placed, which only a `convert` step changes
+    pub fn placed_extension(&self, src: &Path) -> String {
+        self.config.transforms
+            .iter()
+            .flatten()
+            .rev()
+            .find_map(|transform| match transform {
+                Transform::Convert { format } => Some(format.clone()),
+                _ => None,
+            })
+            .or_else(|| src.extension().and_then(|ext| ext.to_str()).map(str::to_string))
+            .unwrap_or_else(|| "tiff".to_string())
+    }
+
+    /// Give a placed slice the modification time of its source, which survives the move out of `tmp`,
+    /// so later runs can tell the slice at a final path came from that source
+    pub fn stamp_source_time(&self, src: &Path, placed: &Path) -> Result<(), Error> {
+        let modified: SystemTime = fs::metadata(src)?.modified()?;
+        fs::File::options().write(true).open(placed)?.set_modified(modified)
+            .context(format!("Failed to set modification time of {:?}", placed))
+    }
+
+    /// Whether the slice at `placed` was placed from `src`, compared to the second
+    /// because some filesystems keep coarser modification times
+    pub fn placed_from(&self, src: &Path, placed: &Path) -> bool {
+        let seconds = |path: &Path| fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|since| since.as_secs());
+        matches!((seconds(src), seconds(placed)), (Some(src), Some(placed)) if src == placed)
+    }
+
+    /// Move a slice out of `tmp` into the final layout, along with any previews already written next to it
+    pub fn finalise_slice(&self, placed: &Path, slice: &ZSlice, visit_dir: &Path, ownership: &Ownership) -> Result<PathBuf, Error> {
+        let ext = placed.extension().and_then(|ext| ext.to_str()).unwrap_or("tiff");
+        let final_path = self.final_path(visit_dir, slice, ext);
+        if final_path.exists() {
+            return Err(anyhow!(format!("Cannot move {:?}, {:?} already exists", placed, final_path)))
+        }
+
+        let final_dir = final_path.parent().context(format!("Final path {:?} has no parent", final_path))?;
+        self.make_dirs(final_dir, self.config.web_user.clone(), self.config.acl_policy, ownership)
+            .context(format!("Failed to create directory {:?}", final_dir))?;
+        self.move_slice(placed, &final_path)?;
+        Ok(final_path)
+    }
+
+    /// Rename a slice along with any previews written next to it
+    pub fn move_slice(&self, from: &Path, to: &Path) -> Result<(), Error> {
+        fs::rename(from, to).context(format!("Failed to move {:?} to {:?}", from, to))?;
+
+        if let Some(preview) = &self.config.preview {
+            let stem = from.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+            let to_stem = to.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+            for suffix in ["", "th"] {
+                let preview_from = from.with_file_name(format!("{}{}.{}", stem, suffix, preview.format));
+                if preview_from.exists() {
+                    let preview_to = to.with_file_name(format!("{}{}.{}", to_stem, suffix, preview.format));
+                    fs::rename(&preview_from, &preview_to).context(format!("Failed to move {:?} to {:?}", preview_from, preview_to))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Move slices left in `<visit>/tmp/<barcode>` into the final layout and update their recorded paths
+    pub fn migrate_tmp(&self, visit_dir: &Path, pool: &Pool) -> Result<Vec<Result<PathBuf, Error>>, Error> {
+        // Recorded paths are canonical, so a relative or symlinked argument must be resolved to find them
+        let visit_dir: &Path = &fs::canonicalize(visit_dir).context(format!("Visit directory {:?} does not exist", visit_dir))?;
+        let visit: Visit = visit_dir
+            .file_name()
+            .and_then(|name| name.to_str())
+            .context(format!("Failed to read visit from {:?}", visit_dir))
+            .and_then(Visit::parse)?;
+        let ownership: Ownership = resolve_ownership(&self.config.ownership, &visit)
+            .context(format!("Could not resolve ownership for visit: {}", visit))?;
+        let preview_format: Option<&str> = self.config.preview.as_ref().map(|preview| preview.format.as_str());
+
+        let barcode_dirs: Vec<PathBuf> = glob(visit_dir.join("tmp").join("*/").to_string_lossy().as_ref())?
+            .filter_map(Result::ok)
+            .collect();
+
+        let mut results: Vec<Result<PathBuf, Error>> = Vec::new();
+        for barcode_dir in barcode_dirs {
+            let barcode: String = barcode_dir.file_name().unwrap_or_default().to_string_lossy().into_owned();
+            let files: Vec<PathBuf> = glob(barcode_dir.join("*").to_string_lossy().as_ref())?
+                .filter_map(Result::ok)
+                .filter(|file| has_tiff_extension(file) || file.extension().and_then(|ext| ext.to_str()) != preview_format)
+                .collect();
+
+            for file in files {
+                if shutdown_requested() {
+                    return Ok(results)
+                }
+                let Some(slice) = ZSlice::parse(&file, &barcode).ok() else {
+                    println!("Leaving {:?} in place, it is not a Z slice", file);
+                    continue
+                };
+
+                let migrated = pool.get_conn().map_err(Error::from).and_then(|mut conn| {
+                    let mut tx = conn.start_transaction(TxOpts::default())?;
+                    let image_id = fetch_sample_image_id_by_path(&file.to_string_lossy().into_owned(), &mut tx)?;
+                    let final_path = self.finalise_slice(&file, &slice, visit_dir, &ownership)?;
+                    let committed = match image_id {
+                        Some(image_id) => update_sample_image_path(image_id, &final_path.to_string_lossy().into_owned(), &mut tx)
+                            .map_err(Error::from)
+                            .and_then(|_| tx.commit().map_err(Error::from)),
+                        None => Ok(()),
+                    };
+                    if let Err(err) = committed {
+                        // Put the slice back so it still matches the path recorded in ISPyB
+                        if let Err(restore_err) = self.move_slice(&final_path, &file) {
+                            println!("Failed to move {:?} back to {:?}: {}", final_path, file, restore_err);
+                        }
+                        return Err(err)
+                    }
+                    Ok(final_path)
+                });
+
+                match &migrated {
+                    OtherOk(final_path) => println!("Moved {:?} to {:?}", file, final_path),
+                    Err(err) => println!("Failed to move {:?}: {}", file, err),
+                }
+                results.push(migrated);
+            }
+
+            let _ = fs::remove_dir(&barcode_dir);
+        }
+
+        Ok(results)
+    }
+
+    /// Every file the imager has written for a barcode
+    pub fn barcode_files(&self, barcode: &String, date_dir: &String, holding_dir: &str) -> Result<Vec<PathBuf>, Error> {
+        let src_dir = Path::new(holding_dir).join(date_dir).join(barcode);
+        let files: Vec<PathBuf> = glob(src_dir.join("*").to_string_lossy().as_ref())
+        .context(format!("Failed to glob source directory for barcode: {}", barcode))?
+        .filter_map(Result::ok)
+        .collect();
+        Ok(files)
+    }
+
+    pub fn get_target_and_move(&self, barcode: &String, date_dir: &String, pool: &Pool, stable: &HashSet<PathBuf>, file_threads: &ThreadPool)  -> Result<Vec<Result<PathBuf, Error>>, Error> {
         //for testing//
         populate_test_data(barcode, pool)?;
         //for testing//
-        let query_result= fetch_visit_info(barcode, pool).context("Failed to retrieve container info from bracode")?;
-        if let None = query_result.clone() {
-            return Err(anyhow!(format!("No container info found for barcode {}", barcode)))
-        }
-        
-        if let None = query_result.clone().unwrap().visit {
-            return Err(anyhow!(format!("No visit directory found for barcode {}", barcode)))
-        }
-        
-        let visit_dir: PathBuf=  self.get_visit_dir(
-            query_result
-            .clone()
-            .unwrap(), 
-            self.config.upload_dir
-            .clone())
+        let query_result: VisitInfo = fetch_visit_info(barcode, pool)
+            .context("Failed to retrieve container info from bracode")?
+            .ok_or_else(|| anyhow!(format!("No container info found for barcode {}", barcode)))?;
+
+        let visit: Visit = query_result.visit
+            .as_deref()
+            .ok_or_else(|| anyhow!(format!("No visit directory found for barcode {}", barcode)))
+            .and_then(Visit::parse)
+            .context(format!("Invalid visit for barcode: {}", barcode))?;
+
+        let ownership: Ownership = resolve_ownership(&self.config.ownership, &visit)
+            .context(format!("Could not resolve ownership for barcode: {}", barcode))?;
+
+        let visit_dir: PathBuf = self.find_or_create_visit_dir(&visit, &query_result, &self.config, pool, &ownership)
             .context(format!("Could not obtain visit directory for barcode: {}", barcode))?;
                         
         let target_dir: PathBuf = visit_dir.join("tmp").join(barcode);
 
-        self.make_dirs(&target_dir, self.config.web_user.clone()).context("Failed to create target directory")?;
+        self.make_dirs(&target_dir, self.config.web_user.clone(), self.config.acl_policy, &ownership).context("Failed to create target directory")?;
 
-        let src_dir = Path::new(&holding_dir).join(date_dir).join(barcode);
-        
-        let files: Vec<PathBuf> = glob(src_dir.join("*").to_string_lossy().as_ref())
-        .context(format!("Failed to glob source directory for barcode: {}", barcode))?
-        .filter_map(Result::ok)
-        .collect();
-    
-        Ok(files
+        let container: InspectionInfo = fetch_container_info(barcode, pool)
+            .context("Failed to retrieve container info from barcode")?
+            .ok_or_else(|| anyhow!(format!("No container info found for barcode {}", barcode)))?;
+
+        let (files, held_back): (Vec<PathBuf>, Vec<PathBuf>) = self.barcode_files(barcode, date_dir, &self.config.holding_dir)?
+        .into_iter()
+        .partition(|file| stable.contains(file));
+
+        let results = file_threads.install(|| {
+            let (mut results, changed) = self.place_files(&files, &visit_dir, &container, pool, barcode, &ownership)?;
+            if self.config.extended_focus {
+                results.extend(self.build_extended_focus(barcode, &files, &held_back, &visit_dir, &changed, &ownership));
+            }
+            Ok(results)
+        });
+        let _ = fs::remove_dir(&target_dir);
+        results
+    }
+
+    /// Move every file of a barcode through `tmp` into the final layout, register and preview it.
+    /// Files already in the final layout are not copied again. Returns the outcome for each file
+    /// along with the paths placed in this run.
+    pub fn place_files(&self, files: &[PathBuf], visit_dir: &Path, container: &InspectionInfo, pool: &Pool, barcode: &String, ownership: &Ownership) -> Result<(FileResults, HashSet<PathBuf>), Error> {
+        let target_dir: PathBuf = visit_dir.join("tmp").join(barcode);
+        let placed: Vec<Result<(ZSlice, PathBuf, bool), Error>> = files
         .par_iter()
         .map(|file| {
-            match self.move_dir(file, &target_dir) {
-                OtherOk(_) => Ok(file.clone()),
+            if shutdown_requested() {
+                return Err(anyhow!(format!("Shutting down, {:?} left for the next run", file)))
+            }
+            let placed = ZSlice::parse(file, barcode).and_then(|slice| {
+                let final_path = self.final_path(visit_dir, &slice, &self.placed_extension(file));
+                if final_path.exists() {
+                    if !self.placed_from(file, &final_path) {
+                        return Err(anyhow!(format!("Cannot place {:?}, {:?} already holds a slice from another source", file, final_path)))
+                    }
+                    // A slice placed by an earlier run whose registration failed is registered now
+                    self.register_slice(&slice, &final_path, container, pool)?;
+                    return Ok((slice, final_path, false))
+                }
+
+                // Everything this slice may write, removed by the shutdown timeout if the slice does not finish first
+                let tmp_name = target_dir.join(file.file_name().unwrap_or_default());
+                let mut tracked: Vec<PathBuf> = vec![tmp_name.with_extension(self.placed_extension(file)), tmp_name, final_path.clone()];
+                if let Some(preview) = &self.config.preview {
+                    tracked.extend(preview_paths(&final_path, &preview.format).into_iter().flatten());
+                }
+                tracked.iter().for_each(|path| signals::track_written(path));
+
+                let placed = (|| {
+                    let tmp_path = self.move_dir(file, &target_dir, &self.config.transforms, &self.limits)?;
+                    self.stamp_source_time(file, &tmp_path).inspect_err(|_| {
+                        let _ = fs::remove_file(&tmp_path);
+                    })?;
+                    let placed = self.finalise_slice(&tmp_path, &slice, visit_dir, ownership).inspect_err(|_| {
+                        let _ = fs::remove_file(&tmp_path);
+                    })?;
+                    self.register_slice(&slice, &placed, container, pool)?;
+
+                    let mut written: Vec<PathBuf> = vec![placed.clone()];
+                    if let Some(preview) = self.config.preview.as_ref().filter(|_| is_tiff(&placed)) {
+                        let _permit = self.limits.memory.acquire(2 * decoded_size(&placed)?);
+                        written.extend(write_previews(&placed, preview, (self.config.thumb_width, self.config.thumb_height))?);
+                    }
+                    self.set_ownership(&written, ownership)?;
+                    Ok(placed)
+                })();
+                signals::forget_written(&tracked);
+                Ok((slice, placed?, true))
+            });
+            if let Err(err) = &placed {
+                println!("Failed to process file {:?}: {}", file, err);
+            }
+            placed
+        })
+        .collect();
+
+        let changed: HashSet<PathBuf> = placed
+            .iter()
+            .filter_map(|result| result.as_ref().ok())
+            .filter(|(_, _, placed_now)| *placed_now)
+            .map(|(_, path, _)| path.clone())
+            .collect();
+        let results: FileResults = placed
+            .into_iter()
+            .map(|result| result.map(|(_, path, _)| path))
+            .collect();
+
+        Ok((results, changed))
+    }
+
+    /// Composite each Z-stack that gained a slice in this run into a single extended focus JPEG alongside
+    /// the slices. Every slice of the stack already in the final layout is used, so a stack placed over
+    /// several runs never replaces its composite with a partial one, and stacks with slices still
+    /// held back wait for the run that places them.
+    pub fn build_extended_focus(&self, barcode: &String, files: &[PathBuf], held_back: &[PathBuf], visit_dir: &Path, changed: &HashSet<PathBuf>, ownership: &Ownership) -> Vec<Result<PathBuf, Error>> {
+        let mut slices: Vec<(ZSlice, PathBuf)> = Vec::new();
+        let mut pending: HashSet<PathBuf> = HashSet::new();
+        for file in files.iter().chain(held_back) {
+            let Some(slice) = ZSlice::parse(file, barcode).ok() else {
+                continue
+            };
+            let final_path = self.final_path(visit_dir, &slice, &self.placed_extension(file));
+            if final_path.exists() {
+                slices.push((slice, final_path));
+            } else if held_back.contains(file) {
+                pending.insert(file.clone());
+                slices.push((slice, file.clone()));
+            }
+        }
+
+        group_stacks(slices, self.config.z_stack_window)
+        .par_iter()
+        .filter(|stack| stack.iter().any(|(_, path)| changed.contains(path)))
+        .filter(|stack| {
+            let waiting = stack.iter().any(|(_, path)| pending.contains(path));
+            if waiting {
+                println!("Extended focus image for {} drop {} waits for slices still being written", barcode, stack[0].0.drop_location());
+            }
+            !waiting
+        })
+        .map(|stack| {
+            let first = &stack[0].0;
+            let timestamp: String = first.imaged_at.chars().filter(|c| c.is_ascii_digit()).collect();
+            let stack_dir = stack[0].1.parent().unwrap_or(Path::new("."));
+            let ef_path = stack_dir.join(format!("{}-{}-{}-EF-{}-{}.jpg", barcode, first.well, first.drop, &timestamp[..8], &timestamp[8..]));
+            let paths: Vec<PathBuf> = stack.iter().map(|(_, path)| path.clone()).collect();
+
+            let frame_size = paths.first().and_then(|path| image::image_dimensions(path).ok())
+                .map(|(width, height)| width as u64 * height as u64)
+                .unwrap_or_default();
+            let _permit = self.limits.memory.acquire(3 * 8 * frame_size);
+            let built = extended_focus(&paths)
+                .and_then(|composite| {
+                    signals::track_written(&ef_path);
+                    let saved = composite.save(&ef_path).map_err(Error::from);
+                    signals::forget_written(std::slice::from_ref(&ef_path));
+                    saved
+                })
+                .and_then(|_| self.set_ownership(std::slice::from_ref(&ef_path), ownership));
+            match built {
+                OtherOk(_) => Ok(ef_path),
                 Err(err) => {
-                    println!("Failed to move file {:?}: {}", file, err);
+                    println!("Failed to build extended focus image {:?}: {}", ef_path, err);
                     Err(err)
                 }
             }
         })
-        .collect())
+        .collect()
+    }
+
+    /// Record a placed Z slice as a sample image against its drop and the container inspection closest in time.
+    /// Slices that already have a row are left alone, so this is safe to call on every run.
+    pub fn register_slice(&self, slice: &ZSlice, placed: &Path, container: &InspectionInfo, pool: &Pool) -> Result<(), Error>{
+        let container_id = container.container_id.context("Container has no ID")?;
+        let container_type = container.container_type.clone().unwrap_or_default();
+        let image_path = placed.to_string_lossy().into_owned();
+
+        let mut conn = pool.get_conn()?;
+        if fetch_sample_image_id_by_path(&image_path, &mut conn)?.is_some() {
+            return Ok(())
+        }
+
+        let position = self.get_position(&slice.drop_location(), &container_type, &self.config.types)?;
+        let sample_id = fetch_sample_id(container_id, position, &mut conn)?
+            .ok_or_else(|| anyhow!(format!("No sample found at drop {} of container {}", slice.drop_location(), container_id)))?;
+        let inspection_id = fetch_nearest_inspection_id(container_id, &slice.imaged_at, &mut conn)?
+            .ok_or_else(|| anyhow!(format!("No inspection found for container {}", container_id)))?;
+
+        let mut tx = conn.start_transaction(TxOpts::default())?;
+        let image_id = insert_sample_image(sample_id, &inspection_id.to_string(), (0.0, 0.0), &mut tx)?;
+        update_sample_image_path(image_id, &image_path, &mut tx)?;
+        update_sample_image_comments(image_id, &format!("z{}", slice.z_height), &mut tx)?;
+        tx.commit()?;
+
+        Ok(())
     }
 }
 
@@ -195,8 +705,30 @@ impl WorkerShared for ZWorker {
         println!("Processing job for Z task");
         let container_dict: HashMap<String, String> = self.get_container_dict(self.date_dirs.clone())?;
 
-        container_dict.par_iter().for_each(|(barcode, date_dir)| {
-            let result = self.get_target_and_move(barcode, date_dir, pool, self.config.holding_dir.clone());
+        let files: Vec<PathBuf> = container_dict
+            .iter()
+            .filter_map(|(barcode, date_dir)| self.barcode_files(barcode, date_dir, &self.config.holding_dir).ok())
+            .flatten()
+            .collect();
+        let stable: HashSet<PathBuf> = stable_files(&files, Duration::from_secs(self.config.quiet_period_secs));
+
+        let barcode_threads: ThreadPool = ThreadPoolBuilder::new()
+            .num_threads(self.config.barcode_concurrency)
+            .thread_name(|index| format!("barcode-{}", index))
+            .build()
+            .context("Failed to build barcode thread pool")?;
+        let file_threads: ThreadPool = ThreadPoolBuilder::new()
+            .num_threads(self.config.worker_threads)
+            .thread_name(|index| format!("file-{}", index))
+            .build()
+            .context("Failed to build file thread pool")?;
+
+        barcode_threads.install(|| container_dict.par_iter().for_each(|(barcode, date_dir)| {
+            if shutdown_requested() {
+                println!("Shutting down, barcode {} left for the next run", barcode);
+                return
+            }
+            let result = self.get_target_and_move(barcode, date_dir, pool, &stable, &file_threads);
             match result {
                 OtherOk(files) => {
                     println!("This barcode has finished processing: {}", barcode);
@@ -211,7 +743,7 @@ impl WorkerShared for ZWorker {
                     println!("{:?}", err);
                 }
             }
-        });
+        }));
 
         Ok(())
     }
@@ -220,31 +752,340 @@ impl WorkerShared for ZWorker {
 pub struct EFWorker {
     pub config: Config,
     pub files: Vec<PathBuf>,
+    pub limits: ResourceLimits,
 }
 
 impl EFWorker {
     pub fn new(config: Config, files: Vec<PathBuf>) -> Self {
-        Self { config, files }
+        let limits = ResourceLimits::new(config.decode_memory_mb, config.io_throttle_mb_s);
+        Self { config, files, limits }
     }
     
-    pub fn handle_ef(&self, xml_datum: &XmlDatum, pool: &Pool) -> Result<(), Error>{
-        println!("Handling EF files");
+    /// Whether every drop of the plate has arrived or already has an image for the inspection, or the
+    /// inspection has waited out the timeout since it was first seen. Inspections that are not ready
+    /// stay in the holding directory for the next run.
+    pub fn inspection_ready(&self, inspection_id: &String, xml_data: &[&XmlDatum], container: &InspectionInfo, gating: &InspectionGating, pool: &Pool) -> Result<bool, Error>{
+        let container_type = container.container_type.clone().unwrap_or_default();
+        let layout = self.config.types.get(&container_type)
+            .context(format!("No plate layout configured for plate type: {}", container_type))?;
+
+        let committed: HashSet<u32> = fetch_imaged_locations(inspection_id, &mut pool.get_conn()?)?
+            .into_iter()
+            .collect();
+        let arrived: HashSet<String> = xml_data
+            .iter()
+            .filter_map(|xml_datum| self.get_xml_text(&xml_datum.root, &["Drop"]).ok())
+            .collect();
+        let (present, missing): (Vec<String>, Vec<String>) = layout.drop_locations()
+            .into_iter()
+            .partition(|drop| {
+                arrived.contains(drop)
+                    || matches!(self.get_position(drop, &container_type, &self.config.types), OtherOk(position) if committed.contains(&position))
+            });
+        if missing.is_empty() {
+            return Ok(true)
+        }
+
+        let now: u64 = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut first_seen: HashMap<String, u64> = self.read_first_seen();
+        let seen_at: u64 = *first_seen.entry(inspection_id.clone()).or_insert(now);
+        self.write_first_seen(&first_seen)?;
+
+        let waited = Duration::from_secs(now.saturating_sub(seen_at));
+        let expected = present.len() + missing.len();
+        if waited < Duration::from_secs(gating.timeout_mins * 60) {
+            println!("Waiting for inspection {}: {} of {} drops imaged", inspection_id, present.len(), expected);
+            return Ok(false)
+        }
+
+        println!("Inspection {} timed out after {} minutes with {} of {} drops imaged, missing: {}",
+            inspection_id, waited.as_secs() / 60, present.len(), expected, missing.join(", "));
+        Ok(true)
+    }
+
+    /// When each gated inspection was first seen, recorded next to the holding directory because
+    /// copies from the imager PC can carry over the original modification time
+    fn first_seen_path(&self) -> PathBuf {
+        PathBuf::from(format!("{}.{}.seen.json", self.config.holding_dir.trim_end_matches('/'), self.config.task.section()))
+    }
+
+    fn read_first_seen(&self) -> HashMap<String, u64> {
+        fs::read_to_string(self.first_seen_path())
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    fn write_first_seen(&self, first_seen: &HashMap<String, u64>) -> Result<(), Error> {
+        let path = self.first_seen_path();
+        fs::write(&path, serde_json::to_string(first_seen)?).context(format!("Failed to record when inspections were first seen in {:?}", path))
+    }
+
+    /// Stop tracking an inspection once its images are committed, so drops arriving later wait afresh
+    fn forget_first_seen(&self, inspection_id: &String) -> Result<(), Error> {
+        let mut first_seen = self.read_first_seen();
+        if first_seen.remove(inspection_id).is_some() {
+            self.write_first_seen(&first_seen)?;
+        }
+        Ok(())
+    }
+
+    /// Stop tracking inspections that no longer have files in the holding directory, such as one
+    /// whose files were removed by hand after the imager never sent its last drops
+    fn prune_first_seen(&self, in_holding: &HashSet<&String>) -> Result<(), Error> {
+        let mut first_seen = self.read_first_seen();
+        let tracked = first_seen.len();
+        first_seen.retain(|inspection_id, _| in_holding.contains(inspection_id));
+        if first_seen.len() < tracked {
+            println!("Stopped waiting for {} inspections with no files left in the holding directory", tracked - first_seen.len());
+            self.write_first_seen(&first_seen)?;
+        }
+        Ok(())
+    }
+
+    pub fn handle_ef(&self, inspection_id: &String, xml_data: &[&XmlDatum], pool: &Pool) -> Result<(), Error>{
+        println!("Handling EF files for inspection: {}", inspection_id);
         //for testing//
-        populate_test_data_for_inspection(&xml_datum.inspection_id, pool)?;
+        populate_test_data_for_inspection(inspection_id, pool)?;
         //for testing//
-        let container: std::result::Result<Option<formulatrix_uploader::InspectionInfo>, mysql::Error> = fetch_inspection_info(&xml_datum.inspection_id, pool);
-        println!("{:?}", container);
+        let container: InspectionInfo = fetch_inspection_info(inspection_id, pool)
+            .context("Failed to retrieve container info from inspection")?
+            .ok_or_else(|| anyhow!(format!("No container info found for inspection {}", inspection_id)))?;
+
+        if let Some(gating) = &self.config.inspection_gating {
+            if !self.inspection_ready(inspection_id, xml_data, &container, gating, pool)? {
+                return Ok(())
+            }
+        }
+
+        let visit: Visit = container.visit
+            .as_deref()
+            .ok_or_else(|| anyhow!(format!("No visit directory found for inspection {}", inspection_id)))
+            .and_then(Visit::parse)
+            .context(format!("Invalid visit for inspection: {}", inspection_id))?;
+
+        let ownership: Ownership = resolve_ownership(&self.config.ownership, &visit)
+            .context(format!("Could not resolve ownership for inspection: {}", inspection_id))?;
+
+        let visit_info = VisitInfo { visit: container.visit.clone(), year: container.year.clone(), beamline: container.beamline.clone() };
+        let visit_dir: PathBuf = self.find_or_create_visit_dir(&visit, &visit_info, &self.config, pool, &ownership)
+            .context(format!("Could not obtain visit directory for inspection: {}", inspection_id))?;
+
+        let target_dir: PathBuf = visit_dir
+            .join("jpegs")
+            .join(container.barcode.clone().unwrap_or_default())
+            .join(inspection_id);
+
+        self.make_dirs(&target_dir, self.config.web_user.clone(), self.config.acl_policy, &ownership).context("Failed to create target directory")?;
+
+        let mut conn = pool.get_conn()?;
+        let mut tx = conn.start_transaction(TxOpts::default())
+            .context(format!("Failed to start transaction for inspection: {}", inspection_id))?;
+        let mut written: Vec<PathBuf> = Vec::new();
+
+        let outcome = xml_data
+            .iter()
+            .try_for_each(|xml_datum| self.upload_image(xml_datum, &container, &target_dir, &mut tx, &mut written))
+            .and_then(|_| self.update_inspection_state(inspection_id, xml_data, &container, &mut tx));
+        let outcome = outcome.and_then(|_| {
+            let placed: Vec<PathBuf> = written.iter().filter(|path| path.exists()).cloned().collect();
+            self.set_ownership(&placed, &ownership)
+        });
+
+        let outcome = match outcome {
+            OtherOk(_) => signals::commit_written(&written, || tx.commit())
+                .context(format!("Failed to commit inspection: {}", inspection_id)),
+            Err(err) => {
+                if let Err(rollback_err) = tx.rollback() {
+                    println!("Failed to roll back inspection {}: {}", inspection_id, rollback_err);
+                }
+                Err(err)
+            }
+        };
 
+        if let Err(err) = outcome {
+            self.remove_written(&written);
+            return Err(err)
+        }
+        self.remove_sources(xml_data);
+        if self.config.inspection_gating.is_some() {
+            if let Err(err) = self.forget_first_seen(inspection_id) {
+                println!("{:?}", err);
+            }
+        }
+
+        println!("This inspection has finished processing: {}", inspection_id);
         Ok(())
     }
 
-    pub fn check_pairs_collect_xml(&self) -> Vec<&PathBuf> {
-        let jpg_file_stems: HashSet<String> = self.files.iter()
+    /// Register one EF image against its sample and place the JPEG, thumbnail and XML in the target directory.
+    /// Every file created is recorded in `written` so it can be removed if the inspection is rolled back.
+    pub fn upload_image<C: Queryable>(&self, xml_datum: &XmlDatum, container: &InspectionInfo, target_dir: &Path, conn: &mut C, written: &mut Vec<PathBuf>) -> Result<(), Error>{
+        let drop = self.get_xml_text(&xml_datum.root, &["Drop"])?;
+        let container_type = container.container_type.clone().unwrap_or_default();
+        let container_id = container.container_id.context("Container has no ID")?;
+        let position = self.get_position(&drop, &container_type, &self.config.types)?;
+
+        let sample_id = fetch_sample_id(container_id, position, conn)?
+            .ok_or_else(|| anyhow!(format!("No sample found at drop {} of container {}", drop, container_id)))?;
+
+        let microns_per_pixel = (
+            self.get_xml_number(&xml_datum.root, &["SizeInMicrons", "Width"])? / self.get_xml_number(&xml_datum.root, &["SizeInPixels", "Width"])?,
+            self.get_xml_number(&xml_datum.root, &["SizeInMicrons", "Height"])? / self.get_xml_number(&xml_datum.root, &["SizeInPixels", "Height"])?,
+        );
+
+        if let Some(image_id) = fetch_inspection_image_id(sample_id, &xml_datum.inspection_id, conn)? {
+            println!("Drop {} already has image {} for inspection {}, skipping {}", drop, image_id, xml_datum.inspection_id, xml_datum.xml);
+            return Ok(())
+        }
+
+        let image_id = insert_sample_image(sample_id, &xml_datum.inspection_id, microns_per_pixel, conn)?;
+
+        let xml_src = Path::new(&xml_datum.xml);
+        let jpg_src = xml_src.with_extension("jpg");
+        let mut image_path = target_dir.join(format!("{}.jpg", image_id));
+        let thumb_path = target_dir.join(format!("{}th.jpg", image_id));
+        let xml_path = target_dir.join(format!("{}.xml", image_id));
+
+        match &self.config.transforms {
+            Some(transforms) if !transforms.is_empty() => {
+                self.record_written(&image_path, written);
+                self.limits.io.consume(fs::metadata(&jpg_src)?.len());
+                // A convert step changes the extension, so the converted name is recorded up front too
+                let converted = transforms.iter().rev().find_map(|transform| match transform {
+                    Transform::Convert { format } => Some(image_path.with_extension(format)),
+                    _ => None,
+                });
+                if let Some(converted) = converted.filter(|converted| *converted != image_path) {
+                    self.record_written(&converted, written);
+                }
+                image_path = self.transform_image(&jpg_src, &image_path, transforms, &self.limits).context("Failed to place image")?;
+                fs::File::open(&image_path)?.sync_all()?;
+            }
+            _ => self.place_file(&jpg_src, &image_path, written).context("Failed to place image")?,
+        }
+        self.place_file(xml_src, &xml_path, written).context("Failed to place XML")?;
+
+        if self.config.thumb_width > 0 && self.config.thumb_height > 0 {
+            let thumb: DynamicImage = open(&image_path)?.thumbnail(self.config.thumb_width, self.config.thumb_height);
+            self.record_written(&thumb_path, written);
+            thumb.save(&thumb_path)?;
+            fs::File::open(&thumb_path)?.sync_all()?;
+        }
+
+        update_sample_image_path(image_id, &image_path.to_string_lossy().into_owned(), conn)?;
+
+        Ok(())
+    }
+
+    /// Mark the inspection as completed once every sample in the container has an image for it,
+    /// recording the imager and imaging time taken from the ImageInfo XML.
+    pub fn update_inspection_state<C: Queryable>(&self, inspection_id: &String, xml_data: &[&XmlDatum], container: &InspectionInfo, conn: &mut C) -> Result<(), Error>{
+        let container_id = container.container_id.context("Container has no ID")?;
+        let (images, samples) = fetch_inspection_progress(inspection_id, container_id, conn)?;
+        if images < samples {
+            println!("Inspection {} has {} of {} images, leaving incomplete", inspection_id, images, samples);
+            return Ok(())
+        }
+
+        // Compared as instants, imagers in different time zones write different UTC offsets
+        let mut imaged_at: Option<(i64, String)> = None;
+        for xml_datum in xml_data {
+            let Some(text) = self.get_xml_text(&xml_datum.root, &["ImagedAt"]).ok() else {
+                continue
+            };
+            let seconds = parse_imaged_at(&text).context(format!("Bad ImagedAt in {}", xml_datum.xml))?;
+            if imaged_at.as_ref().is_none_or(|(latest, _)| seconds > *latest) {
+                imaged_at = Some((seconds, text));
+            }
+        }
+        let (completed_at, imaged_text) = imaged_at
+            .ok_or_else(|| anyhow!(format!("No imaging time found for inspection {}", inspection_id)))?;
+
+        let imager_id: Option<u32> = match xml_data.first().map(|xml_datum| self.get_xml_text(&xml_datum.root, &["Imager"])) {
+            Some(OtherOk(imager_serial)) => {
+                let imager_id = fetch_imager_id(&imager_serial, conn)?;
+                if imager_id.is_none() {
+                    println!("No imager with serial {} in ISPyB, inspection {} keeps its imager", imager_serial, inspection_id);
+                }
+                imager_id
+            }
+            _ => {
+                println!("No imager serial found for inspection {}, leaving its imager unset", inspection_id);
+                None
+            }
+        };
+
+        complete_inspection(inspection_id, imager_id, completed_at, conn)?;
+        update_container_imager(container_id, imager_id, completed_at, conn)?;
+
+        println!("Inspection {} completed at {}", inspection_id, imaged_text);
+        Ok(())
+    }
+
+    /// Copy a file and flush it to disk so a committed row never points at a missing file
+    pub fn place_file(&self, src: &Path, target: &Path, written: &mut Vec<PathBuf>) -> Result<(), Error>{
+        self.record_written(target, written);
+        self.limits.io.consume(fs::metadata(src)?.len());
+        fs::copy(src, target).context(format!("Failed to copy file {:?}", src))?;
+        fs::File::open(target)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Note a file about to be created, for the rollback cleanup and the shutdown timeout
+    pub fn record_written(&self, path: &Path, written: &mut Vec<PathBuf>) {
+        signals::track_written(path);
+        written.push(path.to_path_buf());
+    }
+
+    /// Compensating cleanup for files placed by a rolled back inspection
+    pub fn remove_written(&self, written: &[PathBuf]) {
+        for path in written {
+            if path.exists() {
+                if let Err(err) = fs::remove_file(path) {
+                    println!("Failed to remove file {:?} after rollback: {}", path, err);
+                }
+            }
+        }
+        signals::forget_written(written);
+    }
+
+    /// Remove the XML and JPEG of a committed inspection from the holding directory so later runs do not upload them again
+    pub fn remove_sources(&self, xml_data: &[&XmlDatum]) {
+        for xml_datum in xml_data {
+            let xml_src = Path::new(&xml_datum.xml);
+            for src in [xml_src.to_path_buf(), xml_src.with_extension("jpg")] {
+                if let Err(err) = fs::remove_file(&src) {
+                    println!("Failed to remove {:?} from the holding directory: {}", src, err);
+                }
+            }
+        }
+    }
+
+    pub fn get_xml_text(&self, root: &Element, path: &[&str]) -> Result<String, Error>{
+        let ns = root.tag().ns().context("No namespace found")?;
+        let mut element = root;
+        for tag in path {
+            element = element.children()
+                .find(|child| child.tag().ns() == Some(ns) && child.tag().name() == *tag)
+                .context(format!("{} not found in XML", path.join("/")))?;
+        }
+        Ok(element.text().to_string())
+    }
+
+    pub fn get_xml_number(&self, root: &Element, path: &[&str]) -> Result<f64, Error>{
+        let text = self.get_xml_text(root, path)?;
+        text.trim().parse().context(format!("Failed to parse {} as a number: {}", path.join("/"), text))
+    }
+
+    /// XML files whose JPEG is also present, both having finished transferring
+    pub fn check_pairs_collect_xml<'a>(&self, files: &'a [PathBuf]) -> Vec<&'a PathBuf> {
+        let jpg_file_stems: HashSet<String> = files.iter()
         .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("jpg"))
         .filter_map(|path| path.file_stem().and_then(|stem| stem.to_str()).map(|s| s.to_string()))
         .collect();
 
-        let xml_files: Vec<&PathBuf> = self.files.iter()
+        let xml_files: Vec<&PathBuf> = files.iter()
         .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("xml"))
         .collect();
 
@@ -281,15 +1122,17 @@ impl EFWorker {
 
 impl WorkerShared for EFWorker {
     fn process_job(&self, pool: &Pool) -> Result<(),Error> {
-        println!("Processing job for Z task");
+        println!("Processing job for EF task");
 
-        let xml_files: Vec<&PathBuf> = self.check_pairs_collect_xml();
+        let stable: HashSet<PathBuf> = stable_files(&self.files, Duration::from_secs(self.config.quiet_period_secs));
+        let files: Vec<PathBuf> = self.files.iter().filter(|file| stable.contains(*file)).cloned().collect();
+        let xml_files: Vec<&PathBuf> = self.check_pairs_collect_xml(&files);
 
-        let mut xml_data: Vec<XmlDatum> = xml_files.into_iter()
+        let xml_data: Vec<XmlDatum> = xml_files.into_iter()
         .filter_map(|xml_file| {
             match self.get_inspection_id(xml_file) {
                 OtherOk((inspection_id, nss, root)) => {
-                    let mut xml_datum = XmlDatum{
+                    let xml_datum = XmlDatum{
                         xml: xml_file.to_string_lossy().into_owned(), 
                         inspection_id, 
                         root, 
@@ -305,8 +1148,89 @@ impl WorkerShared for EFWorker {
         })
         .collect();
 
-        let res = self.handle_ef(&xml_data[1    ], pool);
+        let mut inspections: HashMap<String, Vec<&XmlDatum>> = HashMap::new();
+        for xml_datum in &xml_data {
+            inspections.entry(xml_datum.inspection_id.clone()).or_default().push(xml_datum);
+        }
+
+        // Files still being copied may belong to a tracked inspection, so pruning waits until they settle
+        if self.config.inspection_gating.is_some() && files.len() == self.files.len() {
+            if let Err(err) = self.prune_first_seen(&inspections.keys().collect()) {
+                println!("{:?}", err);
+            }
+        }
+
+        for (inspection_id, inspection_data) in &inspections {
+            if shutdown_requested() {
+                println!("Shutting down, inspection {} left for the next run", inspection_id);
+                continue
+            }
+            if let Err(err) = self.handle_ef(inspection_id, inspection_data, pool) {
+                println!("Failed to process inspection: {}", inspection_id);
+                println!("{:?}", err);
+            }
+        }
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use formulatrix_uploader::PlateLayout;
+
+    struct Positions;
+
+    impl WorkerShared for Positions {
+        fn process_job(&self, _pool: &Pool) -> Result<(),Error> {
+            Ok(())
+        }
+    }
+
+    fn types() -> PlateTypes {
+        PlateTypes {
+            CrystalQuickX: PlateLayout { rows: 8, well_per_row: 12, drops_per_well: 2 },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn position_counts_drops_across_rows() {
+        let position = |drop: &str| Positions.get_position(drop, "CrystalQuickX", &types()).unwrap();
+        assert_eq!(position("A01.1"), 1);
+        assert_eq!(position("A01.2"), 2);
+        assert_eq!(position("A02.1"), 3);
+        assert_eq!(position("B01.1"), 25);
+        assert_eq!(position("H12.2"), 192);
+    }
+
+    #[test]
+    fn position_rejects_malformed_drops() {
+        for drop in ["A01", "a01.1", "A00.1", "Ax.1", "A01.x", "01.1", ""] {
+            assert!(Positions.get_position(drop, "CrystalQuickX", &types()).is_err(), "{} should not parse", drop);
+        }
+    }
+
+    #[test]
+    fn tiff_without_extension_is_flipped_by_default() {
+        let dir = std::env::temp_dir().join(format!("uploader-flip-{}", std::process::id()));
+        fs::create_dir_all(dir.join("out")).unwrap();
+        let src = dir.join("slice.img");
+        let img = DynamicImage::ImageLuma8(image::GrayImage::from_raw(1, 2, vec![10, 20]).unwrap());
+        write_pages(&src, &[TiffPage { img, x_resolution: None, y_resolution: None, resolution_unit: None }]).unwrap();
+
+        let placed = Positions.move_dir(&src, &dir.join("out"), &None, &ResourceLimits::new(0, 0)).unwrap();
+        let pages = read_pages(&placed).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(placed.file_name().unwrap(), "slice.img");
+        assert_eq!(pages[0].img.to_luma8().into_raw(), vec![20, 10]);
+    }
+
+    #[test]
+    fn position_needs_a_configured_layout() {
+        assert!(Positions.get_position("A01.1", "Unknown", &types()).is_err());
+        assert!(Positions.get_position("A01.1", "FilmBatch", &types()).is_err());
+    }
+}
@@ -0,0 +1,85 @@
+use formulatrix_uploader::ZSlice;
+use anyhow::{Context, Error, Result};
+use image::{imageops, open, GrayImage, ImageBuffer, Luma, RgbImage};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+type SharpnessMap = ImageBuffer<Luma<f32>, Vec<f32>>;
+
+/// Group slices into stacks of the same drop, splitting where consecutive slices
+/// were imaged more than `window` seconds apart
+pub fn group_stacks(slices: Vec<(ZSlice, PathBuf)>, window: u32) -> Vec<Vec<(ZSlice, PathBuf)>> {
+    let mut drops: HashMap<(String, u8, String), Vec<(ZSlice, PathBuf)>> = HashMap::new();
+    for (slice, path) in slices {
+        let date = slice.imaged_at[..10].to_string();
+        drops.entry((slice.well.clone(), slice.drop, date)).or_default().push((slice, path));
+    }
+
+    let mut stacks: Vec<Vec<(ZSlice, PathBuf)>> = Vec::new();
+    for (_, mut drop_slices) in drops {
+        drop_slices.sort_by(|a, b| a.0.imaged_at.cmp(&b.0.imaged_at).then(a.0.z_height.cmp(&b.0.z_height)));
+
+        let mut stack: Vec<(ZSlice, PathBuf)> = Vec::new();
+        let mut last_seconds: Option<u32> = None;
+        for (slice, path) in drop_slices {
+            let seconds = seconds_of_day(&slice.imaged_at);
+            if matches!(last_seconds, Some(last) if seconds.saturating_sub(last) > window) {
+                stacks.push(std::mem::take(&mut stack));
+            }
+            last_seconds = Some(seconds);
+            stack.push((slice, path));
+        }
+        if !stack.is_empty() {
+            stacks.push(stack);
+        }
+    }
+    stacks
+}
+
+fn seconds_of_day(imaged_at: &str) -> u32 {
+    imaged_at[11..]
+        .split(':')
+        .filter_map(|part| part.parse::<u32>().ok())
+        .fold(0, |seconds, part| seconds * 60 + part)
+}
+
+/// Build an extended depth of field image by taking each pixel from the slice that is sharpest there
+pub fn extended_focus(paths: &[PathBuf]) -> Result<RgbImage, Error> {
+    let mut composite: Option<(RgbImage, SharpnessMap)> = None;
+
+    for path in paths {
+        let img = open(path).context(format!("Failed to open slice {:?}", path))?;
+        let colour: RgbImage = img.to_rgb8();
+        let focus: SharpnessMap = sharpness(&img.to_luma8());
+
+        composite = match composite {
+            None => Some((colour, focus)),
+            Some((mut best_colour, mut best_focus)) => {
+                if best_colour.dimensions() != colour.dimensions() {
+                    anyhow::bail!("Slice {:?} does not match the dimensions of the rest of the stack", path);
+                }
+                for (x, y, pixel) in focus.enumerate_pixels() {
+                    if pixel[0] > best_focus.get_pixel(x, y)[0] {
+                        best_focus.put_pixel(x, y, *pixel);
+                        best_colour.put_pixel(x, y, *colour.get_pixel(x, y));
+                    }
+                }
+                Some((best_colour, best_focus))
+            }
+        };
+    }
+
+    composite.map(|(colour, _)| colour).context("No slices in stack")
+}
+
+/// Local contrast as the absolute Laplacian, smoothed so selection follows regions rather than noise
+fn sharpness(gray: &GrayImage) -> SharpnessMap {
+    let (width, height) = gray.dimensions();
+    let laplacian: SharpnessMap = ImageBuffer::from_fn(width, height, |x, y| {
+        let value = |x: u32, y: u32| gray.get_pixel(x.min(width - 1), y.min(height - 1))[0] as f32;
+        let centre = value(x, y);
+        let neighbours = value(x.saturating_sub(1), y) + value(x + 1, y) + value(x, y.saturating_sub(1)) + value(x, y + 1);
+        Luma([(4.0 * centre - neighbours).abs()])
+    });
+    imageops::blur(&laplacian, 2.0)
+}
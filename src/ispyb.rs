@@ -1,11 +1,12 @@
-use formulatrix_uploader::{Credentials, VisitInfo, InspectionInfo};
-use anyhow::{Context, Result, Error};
+use formulatrix_uploader::{Credentials, VisitInfo, InspectionInfo, Secret, SslMode, Visit};
+use anyhow::{anyhow, Context, Result, Error};
 use mysql::*;
 use mysql::prelude::*;
 use crate::load_creds_from_json;
+use std::path::PathBuf;
 
-pub fn create_conn_pool(database_url: String) -> Result<Pool, mysql::Error>{
-    let pool = Pool::new(database_url.as_str());
+pub fn create_conn_pool(opts: Opts) -> Result<Pool, mysql::Error>{
+    let pool = Pool::new(opts);
     return pool
 }
 
@@ -15,7 +16,8 @@ pub fn fetch_visit_info(barcode: &String, pool: &Pool) -> Result<Option<VisitInf
     let query = r#"
         SELECT 
             CONCAT(p.proposalCode, p.proposalNumber, "-", bs.visit_number) AS visit,
-            DATE_FORMAT(c.blTimeStamp, "%Y") AS year 
+            DATE_FORMAT(c.blTimeStamp, "%Y") AS year,
+            bs.beamLineName AS beamline 
         FROM Container c 
         LEFT OUTER JOIN BLSession bs ON bs.sessionId = c.sessionId 
         LEFT OUTER JOIN Proposal p ON p.proposalId = bs.proposalId 
@@ -26,7 +28,7 @@ pub fn fetch_visit_info(barcode: &String, pool: &Pool) -> Result<Option<VisitInf
     let result = conn.exec_first(
         query,
         (barcode,)
-    )?.map(|(visit, year)| VisitInfo { visit, year });
+    )?.map(|(visit, year, beamline)| VisitInfo { visit, year, beamline });
 
     Ok(result)
 }
@@ -35,11 +37,13 @@ pub fn fetch_inspection_info(inspection_id: &String, pool: &Pool) -> Result<Opti
     let mut conn = pool.get_conn()?;
     let query = r#"
         SELECT 
+            c.barcode, 
             c.containerType, 
             c.containerId, 
             c.sessionId, 
             CONCAT(p.proposalCode, p.proposalNumber, "-", bs.visit_number) AS visit,
-            DATE_FORMAT(c.blTimeStamp, "%Y") AS year 
+            DATE_FORMAT(c.blTimeStamp, "%Y") AS year,
+            bs.beamLineName AS beamline 
         FROM Container c
         INNER JOIN ContainerInspection ci ON ci.containerId = c.containerId
         INNER JOIN Dewar d ON d.dewarId = c.dewarId
@@ -53,23 +57,247 @@ pub fn fetch_inspection_info(inspection_id: &String, pool: &Pool) -> Result<Opti
     let result = conn.exec_first(
         query,
         (inspection_id,)
-    )?.map(|(container_type, container_id, session_id, visit, year)| 
+    )?.map(|(barcode, container_type, container_id, session_id, visit, year, beamline)| 
     InspectionInfo { 
+        barcode, 
         container_type, 
         container_id, 
         session_id, 
         visit, 
-        year });
+        year, 
+        beamline });
 
     Ok(result)
 }
 
-pub fn parse_ispyb_url(file_path: &String) -> Result<String,Error>{
+pub fn fetch_container_info(barcode: &String, pool: &Pool) -> Result<Option<InspectionInfo>, mysql::Error> {
+    let mut conn = pool.get_conn()?;
+    let query = r#"
+        SELECT 
+            c.barcode, 
+            c.containerType, 
+            c.containerId, 
+            c.sessionId, 
+            CONCAT(p.proposalCode, p.proposalNumber, "-", bs.visit_number) AS visit,
+            DATE_FORMAT(c.blTimeStamp, "%Y") AS year,
+            bs.beamLineName AS beamline 
+        FROM Container c
+        LEFT OUTER JOIN BLSession bs ON bs.sessionId = c.sessionId
+        LEFT OUTER JOIN Proposal p ON p.proposalId = bs.proposalId
+        WHERE c.barcode = ?
+        LIMIT 1;
+    "#;
+
+    let result = conn.exec_first(
+        query,
+        (barcode,)
+    )?.map(|(barcode, container_type, container_id, session_id, visit, year, beamline)| 
+    InspectionInfo { 
+        barcode, 
+        container_type, 
+        container_id, 
+        session_id, 
+        visit, 
+        year, 
+        beamline });
+
+    Ok(result)
+}
+
+pub fn fetch_nearest_inspection_id<C: Queryable>(container_id: u32, imaged_at: &String, conn: &mut C) -> Result<Option<u32>, mysql::Error> {
+    let query = r#"
+        SELECT ci.containerInspectionId 
+        FROM ContainerInspection ci 
+        WHERE ci.containerId = ? 
+        ORDER BY ABS(TIMESTAMPDIFF(SECOND, ci.bltimeStamp, ?)) 
+        LIMIT 1;
+    "#;
+
+    conn.exec_first(query, (container_id, imaged_at))
+}
+
+pub fn fetch_sample_image_id_by_path<C: Queryable>(image_path: &String, conn: &mut C) -> Result<Option<u64>, mysql::Error> {
+    conn.exec_first(
+        "SELECT blSampleImageId FROM BLSampleImage WHERE imageFullPath = ? LIMIT 1",
+        (image_path,),
+    )
+}
+
+/// The EF image of a sample for an inspection, ignoring Z slices which are commented with their height
+pub fn fetch_inspection_image_id<C: Queryable>(sample_id: u32, inspection_id: &String, conn: &mut C) -> Result<Option<u64>, mysql::Error> {
+    let query = r#"
+        SELECT bsi.blSampleImageId 
+        FROM BLSampleImage bsi 
+        WHERE bsi.blSampleId = ? AND bsi.containerInspectionId = ? 
+            AND (bsi.comments IS NULL OR bsi.comments NOT REGEXP '^z[0-9]+$') 
+        LIMIT 1;
+    "#;
+
+    conn.exec_first(query, (sample_id, inspection_id))
+}
+
+/// Locations of the samples that already have an EF image for the inspection
+pub fn fetch_imaged_locations<C: Queryable>(inspection_id: &String, conn: &mut C) -> Result<Vec<u32>, mysql::Error> {
+    let query = r#"
+        SELECT DISTINCT s.location 
+        FROM BLSampleImage bsi 
+        INNER JOIN BLSample s ON s.blSampleId = bsi.blSampleId 
+        WHERE bsi.containerInspectionId = ? 
+            AND (bsi.comments IS NULL OR bsi.comments NOT REGEXP '^z[0-9]+$');
+    "#;
+
+    let locations: Vec<Option<u32>> = conn.exec(query, (inspection_id,))?;
+    Ok(locations.into_iter().flatten().collect())
+}
+
+pub fn fetch_sample_id<C: Queryable>(container_id: u32, location: u32, conn: &mut C) -> Result<Option<u32>, mysql::Error> {
+    let query = r#"
+        SELECT s.blSampleId 
+        FROM BLSample s 
+        WHERE s.containerId = ? AND s.location = ? 
+        LIMIT 1;
+    "#;
+
+    conn.exec_first(query, (container_id, location))
+}
+
+pub fn insert_sample_image<C: Queryable>(sample_id: u32, inspection_id: &String, microns_per_pixel: (f64, f64), conn: &mut C) -> Result<u64, Error> {
+    conn.exec_drop(
+        r#"
+        INSERT INTO BLSampleImage (blSampleId, containerInspectionId, micronsPerPixelX, micronsPerPixelY, blTimeStamp)
+        VALUES (?, ?, ?, ?, CURRENT_TIMESTAMP)
+        "#,
+        (sample_id, inspection_id, microns_per_pixel.0, microns_per_pixel.1),
+    )?;
+
+    // Zero means nothing was inserted on this connection, and updating row 0 would touch the wrong image
+    let image_id: Option<u64> = conn.query_first("SELECT LAST_INSERT_ID()")?;
+    image_id
+        .filter(|image_id| *image_id != 0)
+        .ok_or_else(|| anyhow!(format!("No image ID returned for sample {} of inspection {}", sample_id, inspection_id)))
+}
+
+pub fn update_sample_image_path<C: Queryable>(image_id: u64, image_path: &String, conn: &mut C) -> Result<(), mysql::Error> {
+    conn.exec_drop(
+        r#"
+        UPDATE BLSampleImage SET imageFullPath = ? 
+        WHERE blSampleImageId = ?
+        "#,
+        (image_path, image_id),
+    )
+}
+
+pub fn update_sample_image_comments<C: Queryable>(image_id: u64, comments: &String, conn: &mut C) -> Result<(), mysql::Error> {
+    conn.exec_drop(
+        r#"
+        UPDATE BLSampleImage SET comments = ? 
+        WHERE blSampleImageId = ?
+        "#,
+        (comments, image_id),
+    )
+}
+
+pub fn fetch_imager_id<C: Queryable>(imager_serial: &String, conn: &mut C) -> Result<Option<u32>, mysql::Error> {
+    conn.exec_first(
+        "SELECT imagerId FROM Imager WHERE serial = ? LIMIT 1",
+        (imager_serial,),
+    )
+}
+
+/// Days from the session start date to today, negative for sessions that have not started yet
+pub fn fetch_session_start_offset<C: Queryable>(visit: &Visit, conn: &mut C) -> Result<Option<i64>, mysql::Error> {
+    let query = r#"
+        SELECT DATEDIFF(CURDATE(), bs.startDate) 
+        FROM BLSession bs 
+        INNER JOIN Proposal p ON p.proposalId = bs.proposalId 
+        WHERE p.proposalCode = ? AND p.proposalNumber = ? AND bs.visit_number = ? 
+        LIMIT 1;
+    "#;
+
+    let result: Option<Option<i64>> = conn.exec_first(query, (&visit.proposal_code, &visit.proposal_number, visit.session))?;
+    Ok(result.flatten())
+}
+
+/// Samples with an EF image for the inspection and samples in the container. Z slices registered
+/// against the nearest inspection are commented with their height and do not count.
+pub fn fetch_inspection_progress<C: Queryable>(inspection_id: &String, container_id: u32, conn: &mut C) -> Result<(u32, u32), mysql::Error> {
+    let query = r#"
+        SELECT 
+            (SELECT COUNT(DISTINCT bsi.blSampleId) FROM BLSampleImage bsi 
+                WHERE bsi.containerInspectionId = ? 
+                    AND (bsi.comments IS NULL OR bsi.comments NOT REGEXP '^z[0-9]+$')) AS images,
+            (SELECT COUNT(*) FROM BLSample s WHERE s.containerId = ?) AS samples;
+    "#;
+
+    let result: Option<(u32, u32)> = conn.exec_first(query, (inspection_id, container_id))?;
+    Ok(result.unwrap_or_default())
+}
+
+pub fn complete_inspection<C: Queryable>(inspection_id: &String, imager_id: Option<u32>, completed_at: i64, conn: &mut C) -> Result<(), mysql::Error> {
+    conn.exec_drop(
+        r#"
+        UPDATE ContainerInspection 
+        SET state = 'Completed', completedTimeStamp = FROM_UNIXTIME(?), imagerId = COALESCE(?, imagerId) 
+        WHERE containerInspectionId = ?
+        "#,
+        (completed_at, imager_id, inspection_id),
+    )
+}
+
+/// Record the imager and time a container was last imaged, never moving the time back for an older inspection
+pub fn update_container_imager<C: Queryable>(container_id: u32, imager_id: Option<u32>, imaged_at: i64, conn: &mut C) -> Result<(), mysql::Error> {
+    conn.exec_drop(
+        r#"
+        UPDATE Container
+        SET imagerId = COALESCE(?, imagerId),
+            lastImagedTimeStamp = GREATEST(COALESCE(lastImagedTimeStamp, FROM_UNIXTIME(?)), FROM_UNIXTIME(?))
+        WHERE containerId = ?
+        "#,
+        (imager_id, imaged_at, imaged_at, container_id),
+    )
+}
+
+/// Build the ISPyB connection options field by field so the password never ends up in a URL string
+pub fn ispyb_opts(file_path: &String) -> Result<Opts,Error>{
     let database_creds:Credentials = load_creds_from_json(&file_path)?;
+    let password: Option<Secret> = database_creds.resolve_password().context("Failed to read ISPyB password")?;
+    let port: u16 = database_creds.port.try_into().context(format!("Invalid ISPyB port {}", database_creds.port))?;
+    let constraints = PoolConstraints::new(1, 1).context("Invalid pool size")?;
+
+    let ssl_opts: Option<SslOpts> = match database_creds.ssl_mode {
+        SslMode::Disabled => {
+            if database_creds.ssl_ca.is_some() || database_creds.ssl_client_identity.is_some() {
+                return Err(anyhow!("ssl_ca and ssl_client_identity need an ssl_mode other than disabled"))
+            }
+            None
+        }
+        mode => {
+            let identity = database_creds.ssl_client_identity.map(|path| {
+                let identity = ClientIdentity::new(PathBuf::from(path));
+                match &database_creds.ssl_client_identity_password {
+                    Some(password) => identity.with_password(password.expose().to_string()),
+                    None => identity,
+                }
+            });
+            Some(SslOpts::default()
+                .with_root_cert_path(database_creds.ssl_ca.map(PathBuf::from))
+                .with_client_identity(identity)
+                .with_danger_accept_invalid_certs(mode == SslMode::Required)
+                .with_danger_skip_domain_validation(mode != SslMode::VerifyIdentity))
+        }
+    };
 
-    let database_url: String = format!("mysql://{}:{}@{}:{}/{}?pool_min=1&pool_max=1", database_creds.username, database_creds.password, database_creds.host, database_creds.port, database_creds.database);
+    let builder = OptsBuilder::new()
+        .ip_or_hostname(Some(database_creds.host))
+        .tcp_port(port)
+        .socket(database_creds.socket)
+        .user(Some(database_creds.username))
+        .pass(password.map(|password| password.expose().to_string()))
+        .db_name(Some(database_creds.database))
+        .ssl_opts(ssl_opts)
+        .pool_opts(PoolOpts::default().with_constraints(constraints));
 
-    Ok(database_url)
+    Ok(Opts::from(builder))
 }
 
 //for testing//
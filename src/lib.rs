@@ -1,28 +1,55 @@
 use elementtree::Element;
 use serde::Deserialize;
-use diesel::QueryableByName;
+use std::ffi::CString;
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Context, Result};
 
 /// The paths to cofiguration files
 #[derive(Deserialize, Debug)]
 pub struct ConfigPaths {
     /// Path for lists of handled EF files
-    pub up_files_out_dir: String,
+    #[serde(default)]
+    pub up_files_out_dir: Option<String>,
     /// Path for ISPyB credentials
-    pub credentials_path: String,
+    #[serde(default)]
+    pub credentials_path: Option<String>,
+    /// Path for a single TOML or JSON file configuring every task, replacing the per-task files
+    #[serde(default)]
+    pub config_file: Option<String>,
     /// Path for EF handling configuration
-    pub config_file_ef: String,
+    #[serde(default)]
+    pub config_file_ef: Option<String>,
     /// Path for Z handling configuration
-    pub config_file_z: String,
+    #[serde(default)]
+    pub config_file_z: Option<String>,
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Deserialize, Debug, Clone, Default)]
 pub struct PlateLayout {
+    /// Rows of wells, lettered from A
+    pub rows: u8,
     pub well_per_row: u8,
     pub drops_per_well: u8,
 }
 
-#[derive(Deserialize, Debug, Default)]
+impl PlateLayout {
+    /// Every drop location of the plate in the `G01.1` form used by ImageInfo XML
+    pub fn drop_locations(&self) -> Vec<String> {
+        let mut locations: Vec<String> = Vec::new();
+        for row in (b'A'..).take(self.rows as usize) {
+            for column in 1..=self.well_per_row {
+                for drop in 1..=self.drops_per_well {
+                    locations.push(format!("{}{:02}.{}", row as char, column, drop));
+                }
+            }
+        }
+        locations
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
 pub struct PlateTypes {
     pub CrystalQuickX: PlateLayout,
     pub MitegenInSitu: PlateLayout,
@@ -31,7 +58,32 @@ pub struct PlateTypes {
     pub ReferencePlate: PlateLayout,
 }
 
-#[derive(Deserialize, Debug)]
+impl PlateTypes {
+    /// Every configured layout with its container type
+    pub fn layouts(&self) -> [(&str, &PlateLayout); 5] {
+        [
+            ("CrystalQuickX", &self.CrystalQuickX),
+            ("MitegenInSitu", &self.MitegenInSitu),
+            ("MitegenInSitu_3_Drop", &self.MitegenInSitu_3_Drop),
+            ("FilmBatch", &self.FilmBatch),
+            ("ReferencePlate", &self.ReferencePlate),
+        ]
+    }
+
+    /// Look up the layout for an ISPyB container type
+    pub fn get(&self, container_type: &str) -> Option<&PlateLayout> {
+        match container_type {
+            "CrystalQuickX" => Some(&self.CrystalQuickX),
+            "MitegenInSitu" => Some(&self.MitegenInSitu),
+            "MitegenInSitu_3_Drop" => Some(&self.MitegenInSitu_3_Drop),
+            "FilmBatch" => Some(&self.FilmBatch),
+            "ReferencePlate" => Some(&self.ReferencePlate),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
 pub struct LoggingConfig {
     pub filename: String,
     pub max_bytes: u32,
@@ -40,17 +92,27 @@ pub struct LoggingConfig {
     pub level: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct Logging {
     rotating_file: LoggingConfig
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct Config {
     pub upload_dir: String,
     pub holding_dir: String,
-    pub task: String,
+    pub task: Task,
     pub web_user: String,
+    #[serde(default)]
+    pub acl_policy: AclPolicy,
+    #[serde(default)]
+    pub ownership: OwnershipPolicy,
+    /// Visit directory layouts tried in order, see `WorkerShared::get_visit_dir`
+    #[serde(default = "default_visit_dir_templates")]
+    pub visit_dir_templates: Vec<String>,
+    /// Create a missing visit directory for a session that starts near today
+    #[serde(default)]
+    pub create_visit_dir: Option<CreateVisitDirPolicy>,
     pub max_files: u32,
     #[serde(default)]
     pub max_files_in_batch: u32,
@@ -60,22 +122,504 @@ pub struct Config {
     pub thumb_height: u32,
     #[serde(default)]
     pub types: PlateTypes,
+    /// Ordered image transforms applied when placing files, replacing the default TIFF vertical flip
+    #[serde(default)]
+    pub transforms: Option<Vec<Transform>>,
+    /// Browser-viewable copies written next to each placed image
+    #[serde(default)]
+    pub preview: Option<PreviewConfig>,
+    /// Memory in MB that decoded images may hold across all threads, 0 for no limit
+    #[serde(default)]
+    pub decode_memory_mb: u64,
+    /// Threads for file processing, 0 for one per CPU
+    #[serde(default)]
+    pub worker_threads: usize,
+    /// Barcodes processed at the same time, 0 for one per CPU
+    #[serde(default)]
+    pub barcode_concurrency: usize,
+    /// Read rate limit in MB/s on the shared filesystem, 0 for no limit
+    #[serde(default)]
+    pub io_throttle_mb_s: u64,
+    /// Build an extended focus JPEG from each Z-stack
+    #[serde(default)]
+    pub extended_focus: bool,
+    /// Final location of each Z slice relative to the visit directory. Takes the `{barcode}`, `{date}`,
+    /// `{time}`, `{well}`, `{drop}`, `{height}` and `{ext}` placeholders parsed from the file name.
+    #[serde(default = "default_z_layout")]
+    pub z_layout: String,
+    /// Hold EF inspections back until every drop of the plate has an image
+    #[serde(default)]
+    pub inspection_gating: Option<InspectionGating>,
+    /// Seconds a file's size and modification time must stay unchanged before it is processed
+    #[serde(default = "default_quiet_period_secs")]
+    pub quiet_period_secs: u64,
+    /// Lock file keeping a second instance off the holding directory, `<holding_dir>.<task>.lock` by default
+    #[serde(default)]
+    pub lock_file: Option<String>,
+    /// Seconds in-flight files get to finish after SIGTERM or SIGINT
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+    /// Maximum gap in seconds between slices of the same stack
+    #[serde(default = "default_z_stack_window")]
+    pub z_stack_window: u32,
     pub logging: Logging,
 }
 
+impl Config {
+    /// Check the whole configuration up front and report every problem at once, each prefixed
+    /// with the JSON path of the offending field
+    pub fn validate(&self) -> Result<()> {
+        let mut problems: Vec<String> = Vec::new();
+        let mut problem = |path: &str, message: String| problems.push(format!("{}: {}", path, message));
+
+        for (path, dir, write) in [("$.upload_dir", &self.upload_dir, true), ("$.holding_dir", &self.holding_dir, false)] {
+            if let Err(message) = check_dir(Path::new(dir), write) {
+                problem(path, message);
+            }
+        }
+
+        if self.max_files == 0 {
+            problem("$.max_files", "must be greater than 0".to_string());
+        }
+        match (self.thumb_width, self.thumb_height) {
+            (0, 0) if self.task == Task::EF => problem("$.thumb_width", "EF thumbnails need thumb_width and thumb_height".to_string()),
+            (0, 0) => {}
+            (0, _) => problem("$.thumb_width", "must be set when thumb_height is".to_string()),
+            (_, 0) => problem("$.thumb_height", "must be set when thumb_width is".to_string()),
+            _ => {}
+        }
+        if self.worker_threads > MAX_THREADS {
+            problem("$.worker_threads", format!("must be at most {}", MAX_THREADS));
+        }
+        if self.barcode_concurrency > MAX_THREADS {
+            problem("$.barcode_concurrency", format!("must be at most {}", MAX_THREADS));
+        }
+        if self.z_stack_window == 0 {
+            problem("$.z_stack_window", "must be greater than 0".to_string());
+        }
+
+        for (name, layout) in self.types.layouts() {
+            if !(1..=26).contains(&layout.rows) {
+                problem(&format!("$.types.{}.rows", name), "must be between 1 and 26".to_string());
+            }
+            if layout.well_per_row == 0 {
+                problem(&format!("$.types.{}.well_per_row", name), "must be greater than 0".to_string());
+            }
+            if layout.drops_per_well == 0 {
+                problem(&format!("$.types.{}.drops_per_well", name), "must be greater than 0".to_string());
+            }
+        }
+
+        if self.visit_dir_templates.is_empty() {
+            problem("$.visit_dir_templates", "needs at least one template".to_string());
+        }
+        for (index, template) in self.visit_dir_templates.iter().enumerate() {
+            if let Err(message) = check_placeholders(template, &["upload", "year", "visit", "proposal", "beamline"]) {
+                problem(&format!("$.visit_dir_templates[{}]", index), message);
+            }
+        }
+        if let Err(message) = check_placeholders(&self.z_layout, &["barcode", "date", "time", "well", "drop", "height", "ext"]) {
+            problem("$.z_layout", message);
+        } else {
+            for (placeholder, reason) in [("{height}", "slices of a stack"), ("{time}", "inspections of a drop on the same day")] {
+                if !self.z_layout.contains(placeholder) {
+                    problem("$.z_layout", format!("must contain {} so {} do not overwrite each other", placeholder, reason));
+                }
+            }
+        }
+
+        for (field, mode) in [("dir_mode", &self.ownership.dir_mode), ("file_mode", &self.ownership.file_mode)] {
+            if let Some(mode) = mode {
+                if !matches!(u32::from_str_radix(mode, 8), Ok(value) if value <= 0o7777) {
+                    problem(&format!("$.ownership.{}", field), format!("{} is not an octal mode", mode));
+                }
+            }
+        }
+
+        if let Some(preview) = &self.preview {
+            if !can_write_format(&preview.format) {
+                problem("$.preview.format", format!("cannot write image format {}", preview.format));
+            }
+            if let Some(quality) = preview.quality.filter(|quality| !(1..=100).contains(quality)) {
+                problem("$.preview.quality", format!("{} is not between 1 and 100", quality));
+            }
+        }
+
+        for (index, transform) in self.transforms.iter().flatten().enumerate() {
+            let path = format!("$.transforms[{}]", index);
+            match transform {
+                Transform::Rotate { degrees } if ![90, 180, 270].contains(degrees) => {
+                    problem(&format!("{}.degrees", path), format!("{} is not 90, 180 or 270", degrees));
+                }
+                Transform::Crop { width, height, .. } if *width == 0 || *height == 0 => {
+                    problem(&path, "crop width and height must be greater than 0".to_string());
+                }
+                Transform::Convert { format } if !can_write_format(format) => {
+                    problem(&format!("{}.format", path), format!("cannot write image format {}", format));
+                }
+                Transform::Quality { quality } if !(1..=100).contains(quality) => {
+                    problem(&format!("{}.quality", path), format!("{} is not between 1 and 100", quality));
+                }
+                _ => {}
+            }
+        }
+
+        if problems.is_empty() {
+            return Ok(())
+        }
+        Err(anyhow!(format!("{} configuration problem(s):\n  {}", problems.len(), problems.join("\n  "))))
+    }
+}
+
+/// Upper bound on configured thread counts, anything above is almost certainly a typo
+const MAX_THREADS: usize = 1024;
+
+fn check_dir(dir: &Path, write: bool) -> std::result::Result<(), String> {
+    if !dir.is_dir() {
+        return Err(format!("directory {:?} does not exist", dir));
+    }
+    let c_dir = CString::new(dir.as_os_str().as_bytes()).map_err(|err| err.to_string())?;
+    let mode = if write { libc::R_OK | libc::W_OK | libc::X_OK } else { libc::R_OK | libc::X_OK };
+    if unsafe { libc::access(c_dir.as_ptr(), mode) } != 0 {
+        let access = if write { "writable" } else { "readable" };
+        return Err(format!("directory {:?} is not {}: {}", dir, access, std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+fn check_placeholders(template: &str, known: &[&str]) -> std::result::Result<(), String> {
+    let unknown: Vec<&str> = template
+        .split('{')
+        .skip(1)
+        .map(|part| part.split_once('}').map(|(name, _)| name).unwrap_or(part))
+        .filter(|name| !known.contains(name))
+        .collect();
+    if unknown.is_empty() {
+        return Ok(())
+    }
+    Err(format!("unknown placeholder(s) {}", unknown.iter().map(|name| format!("{{{}}}", name)).collect::<Vec<String>>().join(", ")))
+}
+
+fn default_visit_dir_templates() -> Vec<String> {
+    vec![
+        "{upload}/{year}/{visit}".to_string(),
+        "{upload}/{proposal}/{visit}".to_string(),
+    ]
+}
+
+/// Whether images can be written in the format named by `extension`. WebP is refused because without
+/// the libwebp encoder the image crate only writes lossless WebP and ignores the JPEG quality settings.
+fn can_write_format(extension: &str) -> bool {
+    match image::ImageFormat::from_extension(extension) {
+        Some(image::ImageFormat::WebP) | None => false,
+        Some(format) => !matches!(image::ImageOutputFormat::from(format), image::ImageOutputFormat::Unsupported(_)),
+    }
+}
+
+fn default_z_layout() -> String {
+    "imaging/{barcode}/{date}/{well}_{drop}/{time}-z{height}.{ext}".to_string()
+}
+
+fn default_quiet_period_secs() -> u64 {
+    10
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    30
+}
+
+fn default_z_stack_window() -> u32 {
+    60
+}
+
+/// The kind of images a worker handles
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Task {
+    /// Z-stack slices
+    Z,
+    /// Extended focus images with their ImageInfo XML
+    EF,
+}
+
+impl Task {
+    /// The value of the `task` field
+    pub fn name(&self) -> &'static str {
+        match self {
+            Task::Z => "Z",
+            Task::EF => "EF",
+        }
+    }
+
+    /// The section of a unified config file, e.g. `[task.z]`
+    pub fn section(&self) -> &'static str {
+        match self {
+            Task::Z => "z",
+            Task::EF => "ef",
+        }
+    }
+}
+
+/// What to do when the web user's ACL cannot be set on a new directory
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AclPolicy {
+    Ignore,
+    #[default]
+    Warn,
+    Error,
+}
+
+/// When a missing visit directory may be created
+#[derive(Deserialize, Debug, Clone)]
+pub struct CreateVisitDirPolicy {
+    /// Days either side of the session start date in which the directory is created
+    pub window_days: u32,
+}
+
+/// When an EF inspection is processed before all of its images have arrived
+#[derive(Deserialize, Debug, Clone)]
+pub struct InspectionGating {
+    /// Minutes after an inspection is first seen waiting for drops to process it with drops missing
+    pub timeout_mins: u64,
+}
+
+/// Ownership and permissions for created directories and placed files
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct OwnershipPolicy {
+    /// User name to own created paths
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// Group name, `{proposal}` and `{visit}` are replaced for each visit
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Octal mode for created directories, e.g. `2770`
+    #[serde(default)]
+    pub dir_mode: Option<String>,
+    /// Octal mode for placed files, e.g. `660`
+    #[serde(default)]
+    pub file_mode: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PreviewConfig {
+    /// Output format given as a file extension: `jpg` or `png`
+    pub format: String,
+    /// JPEG encoding quality from 1 to 100
+    #[serde(default)]
+    pub quality: Option<u8>,
+    /// Stretch 16-bit grayscale data to the full 8-bit range instead of truncating
+    #[serde(default)]
+    pub contrast_stretch: bool,
+}
+
+/// A single step of the image transform pipeline, e.g. `{ "op": "rotate", "degrees": 90 }`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Transform {
+    FlipHorizontal,
+    FlipVertical,
+    /// Clockwise rotation by 90, 180 or 270 degrees
+    Rotate { degrees: u16 },
+    Crop { x: u32, y: u32, width: u32, height: u32 },
+    /// Change the output format, given as a file extension such as `png`
+    Convert { format: String },
+    /// JPEG encoding quality from 1 to 100
+    Quality { quality: u8 },
+    /// Re-encode the image so no source metadata is carried over, including the resolution tags of a TIFF
+    StripMetadata,
+}
+
+/// A value that is never printed, so `{:?}` on a struct holding it cannot leak it to logs
+#[derive(Deserialize, Clone, Default, PartialEq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: String) -> Self {
+        Secret(value)
+    }
+
+    /// The secret itself, only to be handed to the code that needs it
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"***\"")
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "***")
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Credentials {
     pub database: String,
     pub username: String,
-    pub password: String,
+    /// Plaintext password, prefer one of the sources below
+    #[serde(default)]
+    pub password: Option<Secret>,
+    /// Environment variable holding the password
+    #[serde(default)]
+    pub password_env: Option<String>,
+    /// File holding the password on its first line
+    #[serde(default)]
+    pub password_file: Option<String>,
+    /// MySQL option file such as `~/.my.cnf`, the password is read from its `[client]` section
+    #[serde(default)]
+    pub option_file: Option<String>,
     pub host: String,
     pub port: u32,
+    /// Unix socket to connect through instead of `host` and `port`
+    #[serde(default)]
+    pub socket: Option<String>,
+    #[serde(default)]
+    pub ssl_mode: SslMode,
+    /// CA bundle in PEM or DER format to verify the server against, the system roots are used otherwise
+    #[serde(default)]
+    pub ssl_ca: Option<String>,
+    /// PKCS#12 archive holding the client certificate and key
+    #[serde(default)]
+    pub ssl_client_identity: Option<String>,
+    #[serde(default)]
+    pub ssl_client_identity_password: Option<Secret>,
+}
+
+/// How the connection to ISPyB is encrypted
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SslMode {
+    #[default]
+    Disabled,
+    /// Encrypt without checking the server certificate
+    Required,
+    /// Check the server certificate chain but not the host name it was issued for
+    VerifyCa,
+    /// Check the server certificate chain and that it was issued for the host
+    VerifyIdentity,
+}
+
+impl Credentials {
+    /// Find the password from the first configured of the environment variable, password file,
+    /// option file and plaintext value. `None` connects without a password.
+    pub fn resolve_password(&self) -> Result<Option<Secret>> {
+        if let Some(name) = &self.password_env {
+            let password = std::env::var(name).context(format!("password_env names {}, which is not set", name))?;
+            return Ok(Some(Secret(password)))
+        }
+
+        if let Some(path) = &self.password_file {
+            let path = expand_home(path);
+            let content = std::fs::read_to_string(&path).context(format!("Failed to read password file {:?}", path))?;
+            let password = content.lines().next().unwrap_or_default().to_string();
+            return Ok(Some(Secret(password)))
+        }
+
+        if let Some(path) = &self.option_file {
+            let path = expand_home(path);
+            let content = std::fs::read_to_string(&path).context(format!("Failed to read option file {:?}", path))?;
+            let password = option_file_value(&content, "client", "password")
+                .context(format!("No password in the [client] section of {:?}", path))?;
+            return Ok(Some(Secret(password)))
+        }
+
+        Ok(self.password.clone())
+    }
+}
+
+/// Replace a leading `~` with the home directory
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Read `key` from `[section]` of a MySQL option file, unquoting the value
+fn option_file_value(content: &str, section: &str, key: &str) -> Option<String> {
+    let mut in_section = false;
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+            in_section = name.trim() == section;
+            continue
+        }
+        if !in_section {
+            continue
+        }
+        if let Some((name, value)) = line.split_once('=') {
+            if name.trim().replace('_', "-") == key {
+                let value = value.trim();
+                let unquoted = value
+                    .strip_prefix('"').and_then(|value| value.strip_suffix('"'))
+                    .or_else(|| value.strip_prefix('\'').and_then(|value| value.strip_suffix('\'')))
+                    .unwrap_or(value);
+                return Some(unquoted.to_string())
+            }
+        }
+    }
+    None
 }
 
 #[derive(Debug, Clone)]
 pub struct VisitInfo {
     pub visit: Option<String>,
     pub year: Option<String>,
+    pub beamline: Option<String>,
+}
+
+/// A visit name such as `mx23694-130`, split into the proposal code, proposal number and session number
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Visit {
+    pub proposal_code: String,
+    /// Kept as the digits read from ISPyB, which may be zero padded
+    pub proposal_number: String,
+    pub session: u32,
+}
+
+impl Visit {
+    /// Parse the `CONCAT(proposalCode, proposalNumber, "-", visit_number)` value read from ISPyB
+    pub fn parse(visit: &str) -> Result<Visit> {
+        let code_len = visit.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(visit.len());
+        let (proposal_code, rest) = visit.split_at(code_len);
+        if proposal_code.is_empty() {
+            return Err(anyhow!(format!("Visit {} has no proposal code", visit)));
+        }
+
+        let (number, session) = rest.split_once('-')
+            .context(format!("Visit {} has no session number", visit))?;
+        let is_number = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit());
+        if !is_number(number) {
+            return Err(anyhow!(format!("Visit {} has an invalid proposal number", visit)));
+        }
+        if !is_number(session) {
+            return Err(anyhow!(format!("Visit {} has an invalid session number", visit)));
+        }
+
+        Ok(Visit {
+            proposal_code: proposal_code.to_string(),
+            proposal_number: number.to_string(),
+            session: session.parse().context(format!("Session number of visit {} is out of range", visit))?,
+        })
+    }
+
+    /// The proposal the visit belongs to, e.g. `mx23694`
+    pub fn proposal(&self) -> String {
+        format!("{}{}", self.proposal_code, self.proposal_number)
+    }
+}
+
+impl fmt::Display for Visit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.proposal(), self.session)
+    }
 }
 
 #[derive(Debug)]
@@ -87,11 +631,152 @@ pub struct XmlDatum {
     pub container: Option<VisitInfo>
 }
 
+/// Seconds since the Unix epoch of an ImageInfo `ImagedAt` value such as `2024-10-11T13:21:16.8282699Z`,
+/// honouring the imager's UTC offset
+pub fn parse_imaged_at(imaged_at: &str) -> Result<i64> {
+    time::OffsetDateTime::parse(imaged_at.trim(), &time::format_description::well_known::Rfc3339)
+        .map(|imaged_at| imaged_at.unix_timestamp())
+        .context(format!("Invalid imaging time {}", imaged_at))
+}
+
 #[derive(Debug)]
 pub struct InspectionInfo {
+    pub barcode: Option<String>,
     pub container_type: Option<String>,
     pub container_id: Option<u32>,
     pub session_id: Option<u32>,
     pub visit: Option<String>,
     pub year: Option<String>,
-}
\ No newline at end of file
+    pub beamline: Option<String>,
+}
+/// A Z-stack slice as named by the Formulatrix imager,
+/// e.g. `VMXi-AB7191-F08-1-R1DRP1-FL12-z350-20241010-104130.tiff`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZSlice {
+    pub barcode: String,
+    pub well: String,
+    pub drop: u8,
+    pub z_height: u32,
+    /// Imaging time formatted as a MySQL datetime
+    pub imaged_at: String,
+}
+
+impl ZSlice {
+    pub fn parse(path: &Path, barcode: &str) -> Result<ZSlice> {
+        let stem = path.file_stem()
+            .and_then(|stem| stem.to_str())
+            .context(format!("Failed to read file name of {:?}", path))?;
+        let remainder = stem.strip_prefix(barcode)
+            .and_then(|rest| rest.strip_prefix('-'))
+            .context(format!("File name {} does not start with barcode {}", stem, barcode))?;
+        let parts: Vec<&str> = remainder.split('-').collect();
+
+        let well = parts.first().context(format!("No well in file name {}", stem))?.to_string();
+        let drop: u8 = parts.get(1)
+            .and_then(|drop| drop.parse().ok())
+            .context(format!("No drop number in file name {}", stem))?;
+        let z_index = parts.iter()
+            .position(|part| part.len() > 1 && part.starts_with('z') && part[1..].chars().all(|c| c.is_ascii_digit()))
+            .context(format!("No z height in file name {}", stem))?;
+        let z_height: u32 = parts[z_index][1..].parse()?;
+
+        let digits = |part: &&str, len: usize| part.len() == len && part.chars().all(|c| c.is_ascii_digit());
+        let date = parts.get(z_index + 1).filter(|date| digits(date, 8)).context(format!("No date in file name {}", stem))?;
+        let time = parts.get(z_index + 2).filter(|time| digits(time, 6)).context(format!("No time in file name {}", stem))?;
+        let imaged_at = format!("{}-{}-{} {}:{}:{}", &date[..4], &date[4..6], &date[6..], &time[..2], &time[2..4], &time[4..]);
+
+        Ok(ZSlice { barcode: barcode.to_string(), well, drop, z_height, imaged_at })
+    }
+
+    /// The drop location in the `G01.1` form used by ImageInfo XML
+    pub fn drop_location(&self) -> String {
+        format!("{}.{}", self.well, self.drop)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_password_env_is_an_error() {
+        let credentials: Credentials = serde_json::from_value(serde_json::json!({
+            "database": "ispyb", "username": "uploader", "password": "plaintext",
+            "password_env": "UPLOADER_TEST_UNSET_PASSWORD", "host": "localhost", "port": 3306,
+        })).unwrap();
+        let err = credentials.resolve_password().unwrap_err();
+        assert!(err.to_string().contains("UPLOADER_TEST_UNSET_PASSWORD"));
+    }
+
+    #[test]
+    fn only_writable_image_formats_are_accepted() {
+        for format in ["jpg", "png", "tiff"] {
+            assert!(can_write_format(format), "{} should be writable", format);
+        }
+        for format in ["webp", "dds", "xyz"] {
+            assert!(!can_write_format(format), "{} should not be writable", format);
+        }
+    }
+
+    #[test]
+    fn imaged_at_honours_the_utc_offset() {
+        assert_eq!(parse_imaged_at("2024-10-11T13:21:16.8282699Z").unwrap(), 1728652876);
+        assert_eq!(parse_imaged_at("2024-10-11T14:21:16.8282699+01:00").unwrap(), 1728652876);
+        assert!(parse_imaged_at("2024-10-11T13:21:16").is_err());
+    }
+
+    #[test]
+    fn visit_parses_proposal_and_session() {
+        let visit = Visit::parse("mx23694-130").unwrap();
+        assert_eq!(visit, Visit { proposal_code: "mx".to_string(), proposal_number: "23694".to_string(), session: 130 });
+        assert_eq!(visit.proposal(), "mx23694");
+        assert_eq!(visit.to_string(), "mx23694-130");
+        assert_eq!(Visit::parse("ABC123-2").unwrap().proposal(), "ABC123");
+    }
+
+    #[test]
+    fn visit_keeps_zero_padded_proposal_number() {
+        let visit = Visit::parse("cm01234-1").unwrap();
+        assert_eq!(visit.proposal_number, "01234");
+        assert_eq!(visit.proposal(), "cm01234");
+        assert_eq!(visit.to_string(), "cm01234-1");
+    }
+
+    #[test]
+    fn visit_rejects_malformed_names() {
+        for visit in ["", "23694-130", "mx-130", "mx23694", "mx23694-", "mx23694-1a", "mx2x694-130", "mx23694-130-1", "mx23694-99999999999"] {
+            assert!(Visit::parse(visit).is_err(), "{} should not parse", visit);
+        }
+    }
+
+    #[test]
+    fn zslice_parses_imager_file_names() {
+        let path = Path::new("/holding/VMXi-AB7191-F08-1-R1DRP1-FL12-z350-20241010-104130.tiff");
+        let slice = ZSlice::parse(path, "VMXi-AB7191").unwrap();
+        assert_eq!(slice, ZSlice {
+            barcode: "VMXi-AB7191".to_string(),
+            well: "F08".to_string(),
+            drop: 1,
+            z_height: 350,
+            imaged_at: "2024-10-10 10:41:30".to_string(),
+        });
+        assert_eq!(slice.drop_location(), "F08.1");
+    }
+
+    #[test]
+    fn zslice_rejects_malformed_file_names() {
+        for name in [
+            "OTHER-F08-1-z350-20241010-104130.tiff",
+            "VMXi-AB7191-F08-x-z350-20241010-104130.tiff",
+            "VMXi-AB7191-F08-1-350-20241010-104130.tiff",
+            "VMXi-AB7191-F08-1-z350-2024101-104130.tiff",
+            "VMXi-AB7191-F08-1-z350-abcdefgh-104130.tiff",
+            "VMXi-AB7191-F08-1-z350-20241010-10413x.tiff",
+            "VMXi-AB7191-F08-1-z350-2024\u{e9}01-104130.tiff",
+            "VMXi-AB7191-F08-1-z350-20241010.tiff",
+        ] {
+            assert!(ZSlice::parse(Path::new(name), "VMXi-AB7191").is_err(), "{} should not parse", name);
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use formulatrix_uploader::Config;
+use anyhow::{anyhow, Context, Error, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Advisory lock held for as long as the process works on a holding directory.
+/// The lock is released by the kernel when the file is closed, including on a crash.
+pub struct InstanceLock {
+    file: File,
+    path: PathBuf,
+}
+
+/// The configured lock file, or `<holding_dir>.<task>.lock` next to the holding directory
+pub fn lock_path(config: &Config) -> PathBuf {
+    match &config.lock_file {
+        Some(lock_file) => PathBuf::from(lock_file),
+        None => PathBuf::from(format!("{}.{}.lock", config.holding_dir.trim_end_matches('/'), config.task.section())),
+    }
+}
+
+impl InstanceLock {
+    /// Take the lock at `path`, failing with the other instance's PID if it is running.
+    /// Where `flock` is not supported the recorded PID is all there is to go on, and a lock
+    /// left by a PID that no longer exists is taken over. Otherwise a successful `flock` means
+    /// the previous holder has gone, whatever PID it left behind.
+    pub fn acquire(path: &Path) -> Result<InstanceLock, Error> {
+        let mut file: File = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)
+            .context(format!("Failed to open lock file {:?}", path))?;
+        let holder: Option<i32> = read_pid(&mut file).filter(|pid| *pid != std::process::id() as i32);
+
+        match flock(&file) {
+            Ok(()) => {
+                if let Some(pid) = holder {
+                    println!("Taking over lock {:?} left by PID {}", path, pid);
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                return match holder {
+                    Some(pid) if is_running(pid) => Err(anyhow!(format!(
+                        "Another uploader (PID {}) is already processing this holding directory, lock file {:?}", pid, path))),
+                    _ => Err(anyhow!(format!(
+                        "Lock file {:?} is held by a process that could not be identified, check it is not running before removing the file", path))),
+                };
+            }
+            Err(err) if matches!(err.raw_os_error(), Some(libc::ENOLCK) | Some(libc::EOPNOTSUPP)) => {
+                println!("Locking is not supported for {:?}, relying on the recorded PID", path);
+                match holder {
+                    Some(pid) if is_running(pid) => {
+                        return Err(anyhow!(format!(
+                            "Another uploader (PID {}) is already processing this holding directory, lock file {:?}", pid, path)));
+                    }
+                    Some(pid) => println!("Taking over stale lock {:?} left by PID {}", path, pid),
+                    None => {}
+                }
+            }
+            Err(err) => return Err(Error::from(err).context(format!("Failed to lock {:?}", path))),
+        }
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        writeln!(file, "{}", std::process::id())?;
+        file.sync_all()?;
+        Ok(InstanceLock { file, path: path.to_path_buf() })
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        // Clear the PID rather than deleting the file, so a waiting instance never locks an unlinked file
+        if self.file.set_len(0).is_err() {
+            println!("Failed to clear lock file {:?}", self.path);
+        }
+    }
+}
+
+fn flock(file: &File) -> io::Result<()> {
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn read_pid(file: &mut File) -> Option<i32> {
+    let mut content = String::new();
+    file.read_to_string(&mut content).ok()?;
+    content.trim().parse().ok().filter(|pid| *pid > 0)
+}
+
+fn is_running(pid: i32) -> bool {
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Lock every holding directory that will be processed, reporting each one already in use
+pub fn lock_all(configs: &[Config]) -> Result<Vec<InstanceLock>, Error> {
+    configs
+        .iter()
+        .map(|config| {
+            let path = lock_path(config);
+            if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+                fs::create_dir_all(parent).context(format!("Failed to create lock directory {:?}", parent))?;
+            }
+            InstanceLock::acquire(&path).context(format!("Could not lock {} holding directory {}", config.task.name(), config.holding_dir))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pid_left_by_a_previous_holder_does_not_block() {
+        let path = std::env::temp_dir().join(format!("uploader-lock-{}.lock", std::process::id()));
+        // PID 1 is always running, standing in for a reused PID
+        fs::write(&path, "1\n").unwrap();
+
+        let lock = InstanceLock::acquire(&path);
+        let second = InstanceLock::acquire(&path);
+        let first_held = lock.is_ok();
+        drop(lock);
+        fs::remove_file(&path).unwrap();
+
+        assert!(first_held);
+        assert!(second.is_err());
+    }
+}
@@ -1,11 +1,20 @@
+mod acl;
+mod budget;
 mod fileworker;
+mod focus;
 mod ispyb;
-
-use crate::ispyb::{create_conn_pool, parse_ispyb_url, fetch_visit_info};
+mod lock;
+mod preview;
+mod settings;
+mod signals;
+mod stability;
+mod tiffio;
+
+use crate::ispyb::{create_conn_pool, ispyb_opts};
 use crate::fileworker::{EFWorker, ZWorker, WorkerShared};
+use crate::settings::Settings;
 
-use diesel::result;
-use formulatrix_uploader::{ConfigPaths, Config, Credentials};
+use formulatrix_uploader::{Config, Credentials, Task};
 use serde_json;
 use anyhow::{Context, Result, Error};
 use std::fs::File;
@@ -17,26 +26,48 @@ use serde::de::DeserializeOwned;
 use mysql::*;
 use mysql::prelude::*;
 use std::path::Path;
+use std::time::Duration;
 
 fn main() -> Result<(),Error> {
     dotenvy::dotenv().ok();
-    let config_paths: ConfigPaths = envy::from_env::<ConfigPaths>()
-    .context("Failed to load configuration data from .env file")?;
-
-    let database_url: String = parse_ispyb_url(&config_paths.credentials_path).context("Failed to parse ISPyB URL")?;
-    let pool: Pool = create_conn_pool(database_url).context("Failed to establish connection pool")?;
+    let settings: Settings = Settings::from_env().context("Failed to locate configuration")?;
+
+    let opts: Opts = ispyb_opts(&settings.credentials_path).context("Failed to read ISPyB credentials")?;
+    let pool: Pool = create_conn_pool(opts).context("Failed to establish connection pool")?;
+
+    let args: Vec<String> = std::env::args().collect();
+    let shutdown_timeout: u64 = [Task::EF, Task::Z]
+        .iter()
+        .filter_map(|task| settings.load(*task).ok())
+        .map(|config| config.shutdown_timeout_secs)
+        .max()
+        .unwrap_or(30);
+    signals::listen_for_shutdown(Duration::from_secs(shutdown_timeout)).context("Failed to install shutdown handler")?;
+
+    if args.get(1).map(String::as_str) == Some("migrate-z-tmp") {
+        let config: Config = settings.load(Task::Z).context("Could not load Z config")?;
+        return migrate_z_tmp(config, &args[2..], &pool);
+    }
+    if args.get(1).map(String::as_str) == Some("daemon") {
+        let interval: u64 = args.get(2).map(|secs| secs.parse()).transpose().context("Invalid daemon interval")?.unwrap_or(60);
+        return run_daemon(&settings, &pool, Duration::from_secs(interval));
+    }
     
-    let worker_ef: Box<dyn WorkerShared> = setup_worker(&config_paths.config_file_ef).context("Could not set up EF worker")?;
-    let worker_z: Box<dyn WorkerShared> = setup_worker(&config_paths.config_file_z).context("Could not set up Z worker")?;
+    let _locks = lock::lock_all(&[settings.load(Task::EF)?])?;
+    let worker_ef: Box<dyn WorkerShared> = setup_worker(settings.load(Task::EF)).context("Could not set up EF worker")?;
+    let worker_z: Box<dyn WorkerShared> = setup_worker(settings.load(Task::Z)).context("Could not set up Z worker")?;
 
     //worker_z.process_job(&pool).context("Failed to process job")?;
     worker_ef.process_job(&pool).context("Failed to process job")?;
-    
+
+    if signals::shutdown_requested() {
+        println!("Stopped after finishing in-flight work");
+        signals::exit(signals::EXIT_SHUTDOWN);
+    }
     Ok(())
 }
 
-fn setup_worker(config_path: &String) -> Result<Box<dyn WorkerShared>, Error> {
-    let config: std::result::Result<Config, Error> = load_data_from_json(config_path);
+fn setup_worker(config: Result<Config, Error>) -> Result<Box<dyn WorkerShared>, Error> {
     match glob_files(config) {
 
         Ok((paths, config)) => {
@@ -49,19 +80,15 @@ fn setup_worker(config_path: &String) -> Result<Box<dyn WorkerShared>, Error> {
             })
             .collect();
 
-            match config.task.as_str() {
-                "Z" => {
+            match config.task {
+                Task::Z => {
                     let worker = ZWorker::new(config, path_vector);
                     Ok(Box::new(worker))
                 }
-                "EF" => {
+                Task::EF => {
                     let worker = EFWorker::new(config, path_vector);
                     Ok(Box::new(worker))
                 }
-                _ => {
-                    error!("Unknown task type in config file: {}", config.task);
-                    Err(anyhow::Error::msg("Unknown task type"))
-                }
             }
         }
 
@@ -72,6 +99,81 @@ fn setup_worker(config_path: &String) -> Result<Box<dyn WorkerShared>, Error> {
     }
 }
 
+/// Process the EF task every `interval`, reloading the configuration between batches when a
+/// config file changes or SIGHUP arrives. A configuration that fails validation is reported
+/// and the previous one kept, so a bad edit never stops the daemon. The Z task stays disabled,
+/// as it is for a single run.
+fn run_daemon(settings: &Settings, pool: &Pool, interval: Duration) -> Result<(), Error> {
+    signals::listen_for_reload().context("Failed to install SIGHUP handler")?;
+    let load = || -> Result<Vec<Config>, Error> { Ok(vec![settings.load(Task::EF)?]) };
+    let mut configs: Vec<Config> = load().context("Could not load configuration")?;
+    let mut locks = lock::lock_all(&configs)?;
+    let mut modified = settings.modified();
+    let lock_paths = |configs: &[Config]| configs.iter().map(lock::lock_path).collect::<Vec<PathBuf>>();
+
+    while !signals::shutdown_requested() {
+        let reload = signals::take_reload();
+        if reload || settings.modified() != modified {
+            modified = settings.modified();
+            match load() {
+                Ok(reloaded) if lock_paths(&reloaded) == lock_paths(&configs) => {
+                    println!("Reloaded configuration");
+                    configs = reloaded;
+                }
+                Ok(reloaded) => {
+                    // Release first, a lock file shared by the old and new configuration cannot be locked twice
+                    locks.clear();
+                    match lock::lock_all(&reloaded) {
+                        Ok(reloaded_locks) => {
+                            println!("Reloaded configuration and locked its holding directories");
+                            locks = reloaded_locks;
+                            configs = reloaded;
+                        }
+                        Err(err) => {
+                            println!("Keeping the current configuration, could not lock the reloaded holding directories: {:?}", err);
+                            locks = lock::lock_all(&configs)?;
+                        }
+                    }
+                }
+                Err(err) => println!("Keeping the current configuration, reload failed: {:?}", err),
+            }
+        }
+
+        for config in &configs {
+            let task = config.task;
+            let processed = setup_worker(Ok(config.clone())).and_then(|worker| worker.process_job(pool));
+            if let Err(err) = processed {
+                println!("Failed to process {} job: {:?}", task.name(), err);
+            }
+        }
+
+        signals::sleep(interval);
+    }
+
+    println!("Stopped after finishing in-flight work");
+    signals::exit(signals::EXIT_SHUTDOWN)
+}
+
+/// Move Z slices left in `<visit>/tmp` into the final layout, for each visit directory given
+fn migrate_z_tmp(config: Config, visit_dirs: &[String], pool: &Pool) -> Result<(), Error> {
+    if visit_dirs.is_empty() {
+        anyhow::bail!("Usage: migrate-z-tmp <visit directory>...");
+    }
+
+    let worker = ZWorker::new(config, Vec::new());
+    for visit_dir in visit_dirs {
+        let results = worker.migrate_tmp(Path::new(visit_dir), pool)
+            .context(format!("Failed to migrate visit directory {}", visit_dir))?;
+        let failed = results.iter().filter(|result| result.is_err()).count();
+        println!("Migrated {} of {} slices in {}", results.len() - failed, results.len(), visit_dir);
+        if signals::shutdown_requested() {
+            println!("Stopped migrating after {}", visit_dir);
+            signals::exit(signals::EXIT_SHUTDOWN);
+        }
+    }
+    Ok(())
+}
+
 fn load_from_json<T: DeserializeOwned>(file_path: &String) -> Result<T> {
     let mut file: File = File::open(file_path)
         .with_context(|| format!("Failed to open config file: {}", file_path))?;
@@ -90,10 +192,6 @@ fn load_from_json<T: DeserializeOwned>(file_path: &String) -> Result<T> {
     Ok(parsed_content)
 }
 
-pub fn load_data_from_json(file_path: &String) -> Result<Config> {
-    load_from_json(file_path)
-}
-
 pub fn load_creds_from_json(file_path: &String) -> Result<Credentials> {
     load_from_json(file_path)
 }
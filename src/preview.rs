@@ -0,0 +1,88 @@
+use formulatrix_uploader::PreviewConfig;
+use anyhow::{anyhow, Context, Error, Result};
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, GrayImage, ImageBuffer, ImageFormat, Luma};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Write a browser-viewable copy of `src` and, if a thumbnail size is given, a `th` thumbnail next to it.
+/// Returns the paths written.
+pub fn write_previews(src: &Path, preview: &PreviewConfig, thumb_size: (u32, u32)) -> Result<Vec<PathBuf>, Error> {
+    let img: DynamicImage = image::io::Reader::open(src)?.with_guessed_format()?.decode()
+        .context(format!("Failed to decode {:?} for preview", src))?;
+    let img: DynamicImage = if preview.contrast_stretch {
+        stretch_contrast(&img)
+    } else {
+        img
+    };
+
+    let [preview_path, thumb_path] = preview_paths(src, &preview.format).context("Image has no file name")?;
+    let mut written: Vec<PathBuf> = Vec::new();
+    if preview_path != src {
+        save_preview(&img, &preview_path, preview.quality)?;
+        written.push(preview_path);
+    }
+
+    let (thumb_width, thumb_height) = thumb_size;
+    if thumb_width > 0 && thumb_height > 0 {
+        save_preview(&img.thumbnail(thumb_width, thumb_height), &thumb_path, preview.quality)?;
+        written.push(thumb_path);
+    }
+
+    Ok(written)
+}
+
+/// Where the preview and thumbnail of `src` are written
+pub fn preview_paths(src: &Path, format: &str) -> Option<[PathBuf; 2]> {
+    let stem = src.file_stem().and_then(|stem| stem.to_str())?;
+    Some(["", "th"].map(|suffix| src.with_file_name(format!("{}{}.{}", stem, suffix, format))))
+}
+
+fn save_preview(img: &DynamicImage, path: &Path, quality: Option<u8>) -> Result<(), Error> {
+    match ImageFormat::from_path(path)? {
+        ImageFormat::Jpeg => {
+            let file = fs::File::create(path)?;
+            JpegEncoder::new_with_quality(file, quality.unwrap_or(75)).encode_image(&img.to_rgb8())?;
+        }
+        // Without the libwebp encoder feature the image crate only writes lossless WebP, far too large for previews
+        ImageFormat::WebP => return Err(anyhow!(format!("Cannot write {:?}, WebP previews are not supported", path))),
+        _ => img.to_rgba8().save(path)?,
+    }
+    Ok(())
+}
+
+/// Map the 0.5th to 99.5th percentile of a 16-bit grayscale image onto the full 8-bit range.
+/// Other images are returned unchanged.
+pub fn stretch_contrast(img: &DynamicImage) -> DynamicImage {
+    let gray = match img {
+        DynamicImage::ImageLuma16(gray) => gray,
+        _ => return img.clone(),
+    };
+
+    let mut histogram = vec![0u64; 65536];
+    for pixel in gray.pixels() {
+        histogram[pixel[0] as usize] += 1;
+    }
+
+    let total: u64 = gray.pixels().len() as u64;
+    let percentile = |fraction: f64| -> u16 {
+        let threshold = (total as f64 * fraction) as u64;
+        let mut seen = 0;
+        for (value, count) in histogram.iter().enumerate() {
+            seen += count;
+            if seen > threshold {
+                return value as u16;
+            }
+        }
+        u16::MAX
+    };
+
+    let low = percentile(0.005) as f32;
+    let high = (percentile(0.995) as f32).max(low + 1.0);
+
+    let stretched: GrayImage = ImageBuffer::from_fn(gray.width(), gray.height(), |x, y| {
+        let value = (gray.get_pixel(x, y)[0] as f32 - low) / (high - low);
+        Luma([(value.clamp(0.0, 1.0) * 255.0).round() as u8])
+    });
+    DynamicImage::ImageLuma8(stretched)
+}
@@ -0,0 +1,236 @@
+use formulatrix_uploader::{Config, ConfigPaths, Task};
+use anyhow::{Context, Error, Result};
+use serde_json::{Map, Value};
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Prefix of environment variables overriding configuration fields. Nested fields are joined
+/// with `__`, e.g. `UPLOADER__PREVIEW__QUALITY=90` or `UPLOADER__TASK__Z__WORKER_THREADS=4`.
+const ENV_PREFIX: &str = "UPLOADER__";
+
+/// Where the credentials and worker configuration are read from
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub credentials_path: String,
+    source: Source,
+}
+
+#[derive(Debug, Clone)]
+enum Source {
+    /// One TOML or JSON file with shared fields at the top and `[task.ef]` and `[task.z]` sections
+    Unified(String),
+    /// The older layout of one JSON file per task
+    Separate { ef: String, z: String },
+}
+
+impl Settings {
+    /// Read the configuration layout from the environment. `CONFIG_FILE` selects a unified file,
+    /// otherwise `CONFIG_FILE_EF` and `CONFIG_FILE_Z` point at one file per task.
+    pub fn from_env() -> Result<Settings, Error> {
+        let config_paths: ConfigPaths = envy::from_env::<ConfigPaths>()
+            .context("Failed to load configuration data from .env file")?;
+
+        match config_paths.config_file {
+            Some(config_file) => {
+                let root: Value = read_file(&config_file)?;
+                let from_file = |key: &str| root.get(key).and_then(Value::as_str).map(str::to_string);
+                Ok(Settings {
+                    credentials_path: config_paths.credentials_path
+                        .or_else(|| from_file("credentials_path"))
+                        .context(format!("No credentials_path in {} or CREDENTIALS_PATH", config_file))?,
+                    source: Source::Unified(config_file),
+                })
+            }
+            None => Ok(Settings {
+                credentials_path: config_paths.credentials_path.context("CREDENTIALS_PATH is not set")?,
+                source: Source::Separate {
+                    ef: config_paths.config_file_ef.context("Neither CONFIG_FILE nor CONFIG_FILE_EF is set")?,
+                    z: config_paths.config_file_z.context("Neither CONFIG_FILE nor CONFIG_FILE_Z is set")?,
+                },
+            }),
+        }
+    }
+
+    /// Modification times of the config files, compared between batches to notice edits
+    pub fn modified(&self) -> Vec<Option<SystemTime>> {
+        let paths: Vec<&String> = match &self.source {
+            Source::Unified(path) => vec![path],
+            Source::Separate { ef, z } => vec![ef, z],
+        };
+        paths
+            .into_iter()
+            .map(|path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+            .collect()
+    }
+
+    /// Load and validate the configuration of one task, reading the files again on every call
+    pub fn load(&self, task: Task) -> Result<Config, Error> {
+        let (path, root) = match &self.source {
+            Source::Unified(path) => (path, read_file(path)?),
+            Source::Separate { ef, z } => {
+                let path = if task == Task::EF { ef } else { z };
+                let mut sections = Map::new();
+                sections.insert(task.section().to_string(), read_file(path)?);
+                let mut root = Map::new();
+                root.insert("task".to_string(), Value::Object(sections));
+                (path, Value::Object(root))
+            }
+        };
+
+        let config: Config = serde_json::from_value(task_value(root, task, std::env::vars()))
+            .context(format!("Failed to parse {} config from {}", task.section(), path))?;
+        config.validate().with_context(|| format!("Invalid config file: {}", path))?;
+        Ok(config)
+    }
+}
+
+/// Parse a TOML file by its extension, anything else as JSON
+fn read_file(path: &str) -> Result<Value, Error> {
+    let content: String = fs::read_to_string(path).context(format!("Failed to open config file: {}", path))?;
+    if content.trim().is_empty() {
+        anyhow::bail!("Config file {} is empty", path);
+    }
+
+    match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => toml::from_str(&content).context(format!("Failed to parse TOML in {}", path)),
+        _ => serde_json::from_str(&content).context(format!("Failed to parse JSON in {}", path)),
+    }
+}
+
+/// Lay the task section over the shared fields, then apply environment overrides so they win over
+/// both. Overrides under `TASK__<section>__` apply to that task only and win over shared ones.
+fn task_value(mut root: Value, task: Task, vars: impl Iterator<Item = (String, String)>) -> Value {
+    let section: Value = root
+        .get_mut("task")
+        .and_then(|sections| sections.get_mut(task.section()))
+        .map(Value::take)
+        .unwrap_or(Value::Null);
+    if let Value::Object(shared) = &mut root {
+        shared.remove("task");
+    }
+
+    let mut merged = root;
+    if !section.is_null() {
+        merge(&mut merged, section);
+    }
+
+    let mut shared: Vec<(Vec<String>, String)> = Vec::new();
+    let mut task_only: Vec<(Vec<String>, String)> = Vec::new();
+    for (key, value) in vars {
+        let Some(path) = key.strip_prefix(ENV_PREFIX) else {
+            continue
+        };
+        let path: Vec<String> = path.split("__").map(str::to_lowercase).collect();
+        match path.as_slice() {
+            [first, section, rest @ ..] if first == "task" => {
+                if section == task.section() && !rest.is_empty() {
+                    task_only.push((rest.to_vec(), value));
+                }
+            }
+            _ => shared.push((path, value)),
+        }
+    }
+    for (path, value) in shared.into_iter().chain(task_only) {
+        set_path(&mut merged, &path, value);
+    }
+
+    merged["task"] = Value::String(task.name().to_string());
+    merged
+}
+
+/// Set a nested field, matching existing keys regardless of case so `CRYSTALQUICKX` finds `CrystalQuickX`.
+/// The value is parsed as JSON unless the field it replaces is a string.
+fn set_path(root: &mut Value, path: &[String], value: String) {
+    let Some((first, rest)) = path.split_first() else {
+        *root = match root {
+            Value::String(_) => Value::String(value),
+            _ => serde_json::from_str(&value).unwrap_or(Value::String(value)),
+        };
+        return
+    };
+    if !root.is_object() {
+        *root = Value::Object(Map::new());
+    }
+    let Value::Object(fields) = root else {
+        return
+    };
+
+    let key: String = fields.keys()
+        .find(|key| key.eq_ignore_ascii_case(first))
+        .cloned()
+        .unwrap_or_else(|| first.clone());
+    set_path(fields.entry(key).or_insert(Value::Null), rest, value);
+}
+
+/// Merge objects field by field, anything else in `overlay` replaces `base`
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                merge(base.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn vars(pairs: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect::<Vec<_>>().into_iter()
+    }
+
+    #[test]
+    fn set_path_creates_nested_fields() {
+        let mut root = json!({});
+        set_path(&mut root, &["preview".to_string(), "quality".to_string()], "90".to_string());
+        assert_eq!(root, json!({ "preview": { "quality": 90 } }));
+    }
+
+    #[test]
+    fn set_path_matches_keys_regardless_of_case() {
+        let mut root = json!({ "types": { "CrystalQuickX": { "well_per_row": 12 } } });
+        set_path(&mut root, &["types".to_string(), "crystalquickx".to_string(), "well_per_row".to_string()], "6".to_string());
+        assert_eq!(root, json!({ "types": { "CrystalQuickX": { "well_per_row": 6 } } }));
+    }
+
+    #[test]
+    fn set_path_keeps_strings_as_strings() {
+        let mut root = json!({ "ownership": { "dir_mode": "2770" }, "web_user": "www" });
+        set_path(&mut root, &["ownership".to_string(), "dir_mode".to_string()], "2750".to_string());
+        set_path(&mut root, &["extended_focus".to_string()], "true".to_string());
+        set_path(&mut root, &["web_user".to_string()], "nginx".to_string());
+        assert_eq!(root, json!({ "ownership": { "dir_mode": "2750" }, "web_user": "nginx", "extended_focus": true }));
+    }
+
+    #[test]
+    fn merge_overlays_objects_field_by_field() {
+        let mut base = json!({ "preview": { "format": "jpg", "quality": 80 }, "max_files": 10, "transforms": [1, 2] });
+        merge(&mut base, json!({ "preview": { "quality": 90 }, "transforms": [3] }));
+        assert_eq!(base, json!({ "preview": { "format": "jpg", "quality": 90 }, "max_files": 10, "transforms": [3] }));
+    }
+
+    #[test]
+    fn env_overrides_win_over_task_sections() {
+        let root = json!({ "web_user": "www", "max_files": 10, "task": { "ef": { "web_user": "ef-web", "max_files": 20 }, "z": {} } });
+        let value = task_value(root, Task::EF, vars(&[
+            ("UPLOADER__WEB_USER", "nginx"),
+            ("UPLOADER__MAX_FILES", "30"),
+            ("UPLOADER__TASK__EF__MAX_FILES", "40"),
+            ("UPLOADER__TASK__Z__MAX_FILES", "50"),
+            ("OTHER__MAX_FILES", "60"),
+        ]));
+        assert_eq!(value, json!({ "web_user": "nginx", "max_files": 40, "task": "EF" }));
+    }
+
+    #[test]
+    fn env_overrides_apply_to_legacy_files() {
+        let root = json!({ "task": { "z": { "web_user": "www", "task": "Z" } } });
+        let value = task_value(root, Task::Z, vars(&[("UPLOADER__WEB_USER", "nginx")]));
+        assert_eq!(value, json!({ "web_user": "nginx", "task": "Z" }));
+    }
+}
@@ -0,0 +1,143 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+/// Exit code after SIGTERM or SIGINT once in-flight work has finished
+pub const EXIT_SHUTDOWN: i32 = 3;
+/// Exit code when in-flight work did not finish within the shutdown timeout
+pub const EXIT_SHUTDOWN_TIMEOUT: i32 = 4;
+/// Exit code when a second signal arrives while shutting down
+pub const EXIT_FORCED: i32 = 5;
+
+static RELOAD: AtomicBool = AtomicBool::new(false);
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+/// Files being written by in-flight work that nothing committed yet
+static UNCOMMITTED: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+extern "C" fn on_reload(_: libc::c_int) {
+    RELOAD.store(true, Ordering::SeqCst);
+}
+
+/// Flag a configuration reload whenever the process receives SIGHUP
+pub fn listen_for_reload() -> io::Result<()> {
+    let handler = on_reload as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    if unsafe { libc::signal(libc::SIGHUP, handler) } == libc::SIG_ERR {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Whether SIGHUP arrived since the last call
+pub fn take_reload() -> bool {
+    RELOAD.swap(false, Ordering::SeqCst)
+}
+
+extern "C" fn on_shutdown(_: libc::c_int) {
+    if SHUTDOWN.swap(true, Ordering::SeqCst) {
+        unsafe { libc::_exit(EXIT_FORCED) };
+    }
+}
+
+/// Stop taking new work on SIGTERM or SIGINT. In-flight work gets `timeout` to finish before
+/// the process exits anyway, removing the files it had not committed, and a second signal exits straight away.
+pub fn listen_for_shutdown(timeout: Duration) -> io::Result<()> {
+    let handler = on_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    for signal in [libc::SIGTERM, libc::SIGINT] {
+        if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    thread::Builder::new().name("shutdown".to_string()).spawn(move || {
+        while !shutdown_requested() {
+            thread::sleep(Duration::from_millis(200));
+        }
+        println!("Shutting down, waiting up to {}s for in-flight files", timeout.as_secs());
+        thread::sleep(timeout);
+        println!("In-flight files did not finish within {}s, exiting", timeout.as_secs());
+        // Keep the ledger locked until exit so no worker commits a file removed here
+        let mut uncommitted = uncommitted();
+        for path in uncommitted.drain(..) {
+            match fs::remove_file(&path) {
+                Ok(()) => println!("Removed uncommitted file {:?}", path),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => println!("Failed to remove uncommitted file {:?}: {}", path, err),
+            }
+        }
+        exit(EXIT_SHUTDOWN_TIMEOUT);
+    })?;
+    Ok(())
+}
+
+fn uncommitted() -> MutexGuard<'static, Vec<PathBuf>> {
+    UNCOMMITTED.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Record a file before it is created, so it is removed if the shutdown timeout runs out before it is committed
+pub fn track_written(path: &Path) {
+    uncommitted().push(path.to_path_buf());
+}
+
+/// Stop tracking files that are complete or were already cleaned up
+pub fn forget_written(paths: &[PathBuf]) {
+    uncommitted().retain(|path| !paths.contains(path));
+}
+
+/// Run `commit` and stop tracking `paths` once it succeeds. The shutdown timeout waits for a
+/// commit in progress, so it never removes files a committed row points at.
+pub fn commit_written<T, E>(paths: &[PathBuf], commit: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+    let mut uncommitted = uncommitted();
+    let committed = commit()?;
+    uncommitted.retain(|path| !paths.contains(path));
+    Ok(committed)
+}
+
+/// Whether SIGTERM or SIGINT has arrived, checked before starting each barcode, file or inspection
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+/// Sleep for `duration`, waking early on shutdown
+pub fn sleep(duration: Duration) {
+    let step = Duration::from_millis(200);
+    let mut slept = Duration::ZERO;
+    while slept < duration && !shutdown_requested() {
+        thread::sleep(step.min(duration - slept));
+        slept += step;
+    }
+}
+
+/// Flush logged output and exit
+pub fn exit(code: i32) -> ! {
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+    process::exit(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_committed_files_leave_the_ledger() {
+        let kept = PathBuf::from("/holding/uploader-ledger-committed.jpg");
+        let failed = PathBuf::from("/holding/uploader-ledger-failed.jpg");
+        track_written(&kept);
+        track_written(&failed);
+
+        let committed: Result<(), &str> = commit_written(std::slice::from_ref(&kept), || Ok(()));
+        assert!(committed.is_ok());
+        let rolled_back: Result<(), &str> = commit_written(std::slice::from_ref(&failed), || Err("rolled back"));
+        assert!(rolled_back.is_err());
+        assert!(!uncommitted().contains(&kept));
+        assert!(uncommitted().contains(&failed));
+
+        forget_written(std::slice::from_ref(&failed));
+        assert!(!uncommitted().contains(&failed));
+    }
+}
@@ -0,0 +1,36 @@
+use crate::signals;
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// Size and modification time of a file, `None` once it has gone
+fn snapshot(path: &PathBuf) -> Option<(u64, SystemTime)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.len(), metadata.modified().ok()?))
+}
+
+/// Keep the files whose size and modification time stay the same over `quiet`, so files the
+/// imager is still transferring are left for the next run. Size is compared as well as mtime
+/// because copies from the imager PC can carry over the original modification time.
+pub fn stable_files(files: &[PathBuf], quiet: Duration) -> HashSet<PathBuf> {
+    if quiet.is_zero() || files.is_empty() {
+        return files.iter().cloned().collect();
+    }
+
+    let before: Vec<Option<(u64, SystemTime)>> = files.iter().map(snapshot).collect();
+    signals::sleep(quiet);
+
+    files
+        .iter()
+        .zip(before)
+        .filter(|(path, before)| {
+            let stable = before.is_some() && snapshot(path) == *before;
+            if !stable {
+                println!("File {:?} is still being written, leaving it for the next run", path);
+            }
+            stable
+        })
+        .map(|(path, _)| path.clone())
+        .collect()
+}
@@ -0,0 +1,368 @@
+use anyhow::{anyhow, Context, Error, Result};
+use image::{DynamicImage, ImageBuffer};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, Write};
+use std::path::Path;
+use tiff::decoder::{ChunkType, Decoder, DecodingResult};
+use tiff::encoder::{colortype, Rational, TiffEncoder};
+use tiff::tags::{PhotometricInterpretation, ResolutionUnit, SampleFormat, Tag};
+use tiff::ColorType;
+
+/// One page of a TIFF together with the resolution tags that should survive a rewrite
+pub struct TiffPage {
+    pub img: DynamicImage,
+    pub x_resolution: Option<Rational>,
+    pub y_resolution: Option<Rational>,
+    pub resolution_unit: Option<ResolutionUnit>,
+}
+
+/// Detect a TIFF by a case-insensitive `.tif`/`.tiff` extension or, failing that, by its magic bytes
+pub fn is_tiff(path: &Path) -> bool {
+    has_tiff_extension(path) || has_tiff_magic(path).unwrap_or(false)
+}
+
+pub fn has_tiff_extension(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_ascii_lowercase()).as_deref(),
+        Some("tif") | Some("tiff")
+    )
+}
+
+fn has_tiff_magic(path: &Path) -> Result<bool> {
+    let mut magic = [0u8; 4];
+    File::open(path)?.read_exact(&mut magic)?;
+    Ok(matches!(&magic, b"II*\0" | b"MM\0*" | b"II+\0" | b"MM\0+"))
+}
+
+/// Decode every page of a TIFF at its native bit depth
+pub fn read_pages(src: &Path) -> Result<Vec<TiffPage>, Error> {
+    let mut decoder = Decoder::new(BufReader::new(File::open(src)?))
+        .context(format!("Failed to read TIFF {:?}", src))?;
+    let mut pages: Vec<TiffPage> = Vec::new();
+
+    loop {
+        pages.push(read_page(&mut decoder)?);
+        if !decoder.more_images() {
+            break;
+        }
+        decoder.next_image()?;
+    }
+
+    Ok(pages)
+}
+
+fn read_page<R: Read + Seek>(decoder: &mut Decoder<R>) -> Result<TiffPage, Error> {
+    let (width, height) = decoder.dimensions()?;
+    let colour = decoder.colortype()?;
+
+    let x_resolution = rational_tag(decoder, Tag::XResolution)?;
+    let y_resolution = rational_tag(decoder, Tag::YResolution)?;
+    let resolution_unit = decoder
+        .find_tag_unsigned::<u16>(Tag::ResolutionUnit)?
+        .and_then(ResolutionUnit::from_u16);
+
+    let malformed = || anyhow!("TIFF page data does not match its dimensions");
+    let img = match (colour, decoder.read_image()?) {
+        (ColorType::Gray(8), DecodingResult::U8(data)) => DynamicImage::ImageLuma8(ImageBuffer::from_raw(width, height, data).ok_or_else(malformed)?),
+        (ColorType::Gray(16), DecodingResult::U16(data)) => DynamicImage::ImageLuma16(ImageBuffer::from_raw(width, height, data).ok_or_else(malformed)?),
+        (ColorType::GrayA(8), DecodingResult::U8(data)) => DynamicImage::ImageLumaA8(ImageBuffer::from_raw(width, height, data).ok_or_else(malformed)?),
+        (ColorType::GrayA(16), DecodingResult::U16(data)) => DynamicImage::ImageLumaA16(ImageBuffer::from_raw(width, height, data).ok_or_else(malformed)?),
+        (ColorType::RGB(8), DecodingResult::U8(data)) => DynamicImage::ImageRgb8(ImageBuffer::from_raw(width, height, data).ok_or_else(malformed)?),
+        (ColorType::RGB(16), DecodingResult::U16(data)) => DynamicImage::ImageRgb16(ImageBuffer::from_raw(width, height, data).ok_or_else(malformed)?),
+        (ColorType::RGBA(8), DecodingResult::U8(data)) => DynamicImage::ImageRgba8(ImageBuffer::from_raw(width, height, data).ok_or_else(malformed)?),
+        (ColorType::RGBA(16), DecodingResult::U16(data)) => DynamicImage::ImageRgba16(ImageBuffer::from_raw(width, height, data).ok_or_else(malformed)?),
+        (colour, _) => return Err(anyhow!(format!("Unsupported TIFF colour type: {:?}", colour))),
+    };
+
+    Ok(TiffPage { img, x_resolution, y_resolution, resolution_unit })
+}
+
+fn rational_tag<R: Read + Seek>(decoder: &mut Decoder<R>, tag: Tag) -> Result<Option<Rational>, Error> {
+    Ok(match decoder.find_tag(tag)? {
+        Some(tiff::decoder::ifd::Value::Rational(n, d)) => Some(Rational { n, d }),
+        _ => None,
+    })
+}
+
+/// Grayscale with an unassociated alpha channel, which the tiff crate has no encoder colour type for
+struct GrayA8;
+
+impl colortype::ColorType for GrayA8 {
+    type Inner = u8;
+    const TIFF_VALUE: PhotometricInterpretation = PhotometricInterpretation::BlackIsZero;
+    const BITS_PER_SAMPLE: &'static [u16] = &[8, 8];
+    const SAMPLE_FORMAT: &'static [SampleFormat] = &[SampleFormat::Uint; 2];
+}
+
+struct GrayA16;
+
+impl colortype::ColorType for GrayA16 {
+    type Inner = u16;
+    const TIFF_VALUE: PhotometricInterpretation = PhotometricInterpretation::BlackIsZero;
+    const BITS_PER_SAMPLE: &'static [u16] = &[16, 16];
+    const SAMPLE_FORMAT: &'static [SampleFormat] = &[SampleFormat::Uint; 2];
+}
+
+/// `ExtraSamples` value marking the last sample as unassociated alpha
+const UNASSOCIATED_ALPHA: u16 = 2;
+
+/// Write pages to a TIFF, keeping their pixel type, bit depth and resolution tags
+pub fn write_pages(target: &Path, pages: &[TiffPage]) -> Result<(), Error> {
+    let mut encoder = TiffEncoder::new(BufWriter::new(File::create(target)?))?;
+
+    for page in pages {
+        match &page.img {
+            DynamicImage::ImageLuma8(buf) => write_page::<_, colortype::Gray8>(&mut encoder, page, buf.as_raw())?,
+            DynamicImage::ImageLuma16(buf) => write_page::<_, colortype::Gray16>(&mut encoder, page, buf.as_raw())?,
+            DynamicImage::ImageLumaA8(buf) => write_page::<_, GrayA8>(&mut encoder, page, buf.as_raw())?,
+            DynamicImage::ImageLumaA16(buf) => write_page::<_, GrayA16>(&mut encoder, page, buf.as_raw())?,
+            DynamicImage::ImageRgb8(buf) => write_page::<_, colortype::RGB8>(&mut encoder, page, buf.as_raw())?,
+            DynamicImage::ImageRgb16(buf) => write_page::<_, colortype::RGB16>(&mut encoder, page, buf.as_raw())?,
+            DynamicImage::ImageRgba8(buf) => write_page::<_, colortype::RGBA8>(&mut encoder, page, buf.as_raw())?,
+            DynamicImage::ImageRgba16(buf) => write_page::<_, colortype::RGBA16>(&mut encoder, page, buf.as_raw())?,
+            img => return Err(anyhow!(format!("Unsupported pixel type for TIFF output: {:?}", img.color()))),
+        }
+    }
+
+    Ok(())
+}
+
+fn write_page<W: Write + Seek, C: colortype::ColorType>(encoder: &mut TiffEncoder<W>, page: &TiffPage, data: &[C::Inner]) -> Result<(), Error>
+where
+    [C::Inner]: tiff::encoder::TiffValue,
+{
+    let mut image = encoder.new_image::<C>(page.img.width(), page.img.height())?;
+    if C::BITS_PER_SAMPLE.len() == 2 {
+        image.encoder().write_tag(Tag::ExtraSamples, UNASSOCIATED_ALPHA)?;
+    }
+    if let Some(unit) = page.resolution_unit {
+        image.resolution_unit(unit);
+    }
+    if let Some(x_resolution) = &page.x_resolution {
+        image.x_resolution(x_resolution.clone());
+    }
+    if let Some(y_resolution) = &page.y_resolution {
+        image.y_resolution(y_resolution.clone());
+    }
+    image.write_data(data)?;
+    Ok(())
+}
+
+/// Bytes needed to hold every page of a TIFF fully decoded
+pub fn decoded_size(src: &Path) -> Result<u64, Error> {
+    let mut decoder = Decoder::new(BufReader::new(File::open(src)?))?;
+    let mut bytes: u64 = 0;
+
+    loop {
+        let (width, height) = decoder.dimensions()?;
+        bytes += width as u64 * height as u64 * bytes_per_pixel(decoder.colortype()?);
+        if !decoder.more_images() {
+            break;
+        }
+        decoder.next_image()?;
+    }
+
+    Ok(bytes)
+}
+
+/// Bytes held at once by `stream_flip_vertical`: two source bands and one output strip, for the largest page
+pub fn streaming_size(src: &Path) -> Result<u64, Error> {
+    let mut decoder = Decoder::new(BufReader::new(File::open(src)?))?;
+    let mut bytes: u64 = 0;
+
+    loop {
+        let (width, _) = decoder.dimensions()?;
+        let band_height = decoder.chunk_dimensions().1 as u64;
+        bytes = bytes.max(3 * band_height * width as u64 * bytes_per_pixel(decoder.colortype()?));
+        if !decoder.more_images() {
+            break;
+        }
+        decoder.next_image()?;
+    }
+
+    Ok(bytes)
+}
+
+fn bytes_per_pixel(colour: ColorType) -> u64 {
+    let (samples, bits) = match colour {
+        ColorType::Gray(bits) | ColorType::Palette(bits) => (1, bits),
+        ColorType::GrayA(bits) => (2, bits),
+        ColorType::RGB(bits) | ColorType::YCbCr(bits) => (3, bits),
+        ColorType::RGBA(bits) | ColorType::CMYK(bits) => (4, bits),
+    };
+    samples * (bits as u64).div_ceil(8)
+}
+
+/// Whether every page can be flipped by `stream_flip_vertical`:
+/// chunky 8 or 16-bit grayscale, RGB or RGBA
+pub fn can_stream(src: &Path) -> Result<bool, Error> {
+    let mut decoder = Decoder::new(BufReader::new(File::open(src)?))?;
+
+    loop {
+        let planar = decoder.find_tag_unsigned::<u16>(Tag::PlanarConfiguration)?.unwrap_or(1);
+        let supported = matches!(
+            decoder.colortype()?,
+            ColorType::Gray(8) | ColorType::Gray(16) | ColorType::RGB(8) | ColorType::RGB(16) | ColorType::RGBA(8) | ColorType::RGBA(16)
+        );
+        if planar != 1 || !supported {
+            return Ok(false);
+        }
+        if !decoder.more_images() {
+            break;
+        }
+        decoder.next_image()?;
+    }
+
+    Ok(true)
+}
+
+/// Vertically flip every page of a TIFF without decoding whole pages.
+/// Output strips are assembled from at most two source strips or tile rows at a time.
+pub fn stream_flip_vertical(src: &Path, target: &Path) -> Result<(), Error> {
+    let mut decoder = Decoder::new(BufReader::new(File::open(src)?))
+        .context(format!("Failed to read TIFF {:?}", src))?;
+    let mut encoder = TiffEncoder::new(BufWriter::new(File::create(target)?))?;
+
+    loop {
+        match decoder.colortype()? {
+            ColorType::Gray(8) => flip_page::<_, _, colortype::Gray8>(&mut decoder, &mut encoder, as_u8)?,
+            ColorType::Gray(16) => flip_page::<_, _, colortype::Gray16>(&mut decoder, &mut encoder, as_u16)?,
+            ColorType::RGB(8) => flip_page::<_, _, colortype::RGB8>(&mut decoder, &mut encoder, as_u8)?,
+            ColorType::RGB(16) => flip_page::<_, _, colortype::RGB16>(&mut decoder, &mut encoder, as_u16)?,
+            ColorType::RGBA(8) => flip_page::<_, _, colortype::RGBA8>(&mut decoder, &mut encoder, as_u8)?,
+            ColorType::RGBA(16) => flip_page::<_, _, colortype::RGBA16>(&mut decoder, &mut encoder, as_u16)?,
+            colour => return Err(anyhow!(format!("Unsupported TIFF colour type for streaming: {:?}", colour))),
+        }
+        if !decoder.more_images() {
+            break;
+        }
+        decoder.next_image()?;
+    }
+
+    Ok(())
+}
+
+fn as_u8(result: DecodingResult) -> Option<Vec<u8>> {
+    match result {
+        DecodingResult::U8(data) => Some(data),
+        _ => None,
+    }
+}
+
+fn as_u16(result: DecodingResult) -> Option<Vec<u16>> {
+    match result {
+        DecodingResult::U16(data) => Some(data),
+        _ => None,
+    }
+}
+
+fn flip_page<R, W, C>(decoder: &mut Decoder<R>, encoder: &mut TiffEncoder<W>, samples: fn(DecodingResult) -> Option<Vec<C::Inner>>) -> Result<(), Error>
+where
+    R: Read + Seek,
+    W: Write + Seek,
+    C: colortype::ColorType,
+    C::Inner: Copy,
+    [C::Inner]: tiff::encoder::TiffValue,
+{
+    let (width, height) = decoder.dimensions()?;
+    let row_samples = width as usize * C::BITS_PER_SAMPLE.len();
+    let band_height = decoder.chunk_dimensions().1;
+
+    let x_resolution = rational_tag(decoder, Tag::XResolution)?;
+    let y_resolution = rational_tag(decoder, Tag::YResolution)?;
+    let resolution_unit = decoder
+        .find_tag_unsigned::<u16>(Tag::ResolutionUnit)?
+        .and_then(ResolutionUnit::from_u16);
+
+    let mut image = encoder.new_image::<C>(width, height)?;
+    if let Some(unit) = resolution_unit {
+        image.resolution_unit(unit);
+    }
+    if let Some(x_resolution) = x_resolution {
+        image.x_resolution(x_resolution);
+    }
+    if let Some(y_resolution) = y_resolution {
+        image.y_resolution(y_resolution);
+    }
+    image.rows_per_strip(band_height)?;
+
+    let mut bands: Vec<(u32, Vec<C::Inner>)> = Vec::with_capacity(2);
+    let mut row = 0;
+    while row < height {
+        let rows = band_height.min(height - row);
+        let mut strip: Vec<C::Inner> = Vec::with_capacity(rows as usize * row_samples);
+
+        for out_row in row..row + rows {
+            let src_row = height - 1 - out_row;
+            let band = src_row / band_height;
+            if !bands.iter().any(|(index, _)| *index == band) {
+                if bands.len() == 2 {
+                    bands.remove(0);
+                }
+                bands.push((band, read_band(decoder, band, row_samples, samples)?));
+            }
+            let data = &bands.iter().find(|(index, _)| *index == band).context("Band not cached")?.1;
+            let offset = (src_row - band * band_height) as usize * row_samples;
+            strip.extend_from_slice(&data[offset..offset + row_samples]);
+        }
+
+        image.write_strip(&strip)?;
+        row += rows;
+    }
+
+    image.finish()?;
+    Ok(())
+}
+
+/// Decode one strip, or one row of tiles stitched together, as full-width rows
+fn read_band<R: Read + Seek, T: Copy>(decoder: &mut Decoder<R>, band: u32, row_samples: usize, samples: fn(DecodingResult) -> Option<Vec<T>>) -> Result<Vec<T>, Error> {
+    let malformed = || anyhow!("TIFF chunk has an unexpected sample type");
+
+    match decoder.get_chunk_type() {
+        ChunkType::Strip => samples(decoder.read_chunk(band)?).ok_or_else(malformed),
+        ChunkType::Tile => {
+            let (width, _) = decoder.dimensions()?;
+            let tile_width = decoder.chunk_dimensions().0;
+            let tiles_across = width.div_ceil(tile_width);
+            let pixel_samples = row_samples / width as usize;
+
+            let mut rows: Vec<T> = Vec::new();
+            let mut column_offset = 0;
+            for tile in band * tiles_across..(band + 1) * tiles_across {
+                let (data_width, data_height) = decoder.chunk_data_dimensions(tile);
+                let data = samples(decoder.read_chunk(tile)?).ok_or_else(malformed)?;
+                let tile_row_samples = data_width as usize * pixel_samples;
+                if rows.is_empty() {
+                    rows = data.first().map(|first| vec![*first; data_height as usize * row_samples]).unwrap_or_default();
+                }
+                for tile_row in 0..data_height as usize {
+                    let start = tile_row * row_samples + column_offset;
+                    rows[start..start + tile_row_samples].copy_from_slice(&data[tile_row * tile_row_samples..(tile_row + 1) * tile_row_samples]);
+                }
+                column_offset += tile_row_samples;
+            }
+            Ok(rows)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GrayAlphaImage;
+
+    #[test]
+    fn gray_alpha_pages_keep_two_samples() {
+        let target = std::env::temp_dir().join(format!("uploader-graya-{}.tiff", std::process::id()));
+        let img = DynamicImage::ImageLumaA8(GrayAlphaImage::from_raw(2, 1, vec![1, 255, 2, 128]).unwrap());
+        write_pages(&target, &[TiffPage { img, x_resolution: None, y_resolution: None, resolution_unit: None }]).unwrap();
+
+        let mut decoder = Decoder::new(BufReader::new(File::open(&target).unwrap())).unwrap();
+        let samples = decoder.get_tag_u32(Tag::SamplesPerPixel).unwrap();
+        let extra = decoder.get_tag_u32(Tag::ExtraSamples).unwrap();
+        std::fs::remove_file(&target).unwrap();
+
+        assert_eq!(samples, 2);
+        assert_eq!(extra, UNASSOCIATED_ALPHA as u32);
+    }
+}